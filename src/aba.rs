@@ -0,0 +1,119 @@
+#![doc = r"ABA routing numbers and the Fed ACH participant directory"]
+
+use std::{collections::HashMap, io::BufRead, path::Path};
+
+/// Whether `routing` is nine digits passing the ABA mod-10 checksum,
+/// `3(d1 + d4 + d7) + 7(d2 + d5 + d8) + (d3 + d6 + d9) ≡ 0 (mod 10)`.
+pub fn is_valid_routing(routing: &str) -> bool {
+    if routing.len() != 9 || !routing.bytes().all(|b| b.is_ascii_digit()) {
+        return false;
+    }
+    let sum: u32 = routing
+        .bytes()
+        .zip([3, 7, 1].into_iter().cycle())
+        .map(|(b, weight)| u32::from(b - b'0') * weight)
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+/// A participant of the Fed ACH directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bank {
+    pub routing: String,
+    pub name: String,
+    pub city: String,
+    pub state: String,
+}
+
+/// The Fed ACH participant directory, loaded from the fixed width
+/// `FedACHdir.txt` the Federal Reserve publishes: one 155 character line
+/// per routing number, with the name at columns 36-71, the city at
+/// 108-127 and the state at 128-129.
+#[derive(Debug, Clone, Default)]
+pub struct AchDirectory {
+    banks: HashMap<String, Bank>,
+}
+
+impl AchDirectory {
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = std::fs::File::open(path)?;
+        Self::read(std::io::BufReader::new(file))
+    }
+
+    /// Load the file at `FED_ACH_DIRECTORY`, if set.
+    pub fn from_env() -> std::io::Result<Option<Self>> {
+        match std::env::var("FED_ACH_DIRECTORY") {
+            Ok(path) => Self::load(path).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Lines that are too short or do not start with a routing number are
+    /// skipped.
+    pub fn read(input: impl BufRead) -> std::io::Result<Self> {
+        let mut banks = HashMap::new();
+        for line in input.lines() {
+            let line = line?;
+            let field = |from: usize, to: usize| line.get(from - 1..to).unwrap_or_default().trim();
+
+            let routing = field(1, 9);
+            if routing.len() != 9 || !routing.bytes().all(|b| b.is_ascii_digit()) {
+                continue;
+            }
+            let bank = Bank {
+                routing: routing.to_string(),
+                name: field(36, 71).to_string(),
+                city: field(108, 127).to_string(),
+                state: field(128, 129).to_string(),
+            };
+            banks.insert(bank.routing.clone(), bank);
+        }
+        Ok(Self { banks })
+    }
+
+    pub fn lookup(&self, routing: &str) -> Option<&Bank> {
+        self.banks.get(routing)
+    }
+
+    pub fn len(&self) -> usize {
+        self.banks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.banks.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routing_checksum() {
+        assert!(is_valid_routing("021000021"));
+        assert!(is_valid_routing("011000015"));
+        assert!(!is_valid_routing("021000022"));
+        assert!(!is_valid_routing("02100002"));
+        assert!(!is_valid_routing("02100002a"));
+
+        let directory = AchDirectory::read(
+            format!(
+                "{:<35}{:<36}{:<36}{:<20}{:<2}\nnot a record\n",
+                "021000021O0210000201020805000000000",
+                "JPMORGAN CHASE BANK, NA",
+                "PO BOX 27",
+                "TAMPA",
+                "FL",
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(directory.len(), 1);
+        let bank = directory.lookup("021000021").unwrap();
+        assert_eq!(
+            (bank.name.as_str(), bank.city.as_str(), bank.state.as_str()),
+            ("JPMORGAN CHASE BANK, NA", "TAMPA", "FL")
+        );
+        assert!(directory.lookup("011000015").is_none());
+    }
+}
@@ -0,0 +1,270 @@
+#![doc = r"users, their passwords, and who is signed in"]
+
+use std::{collections::BTreeMap, future::Future, pin::Pin};
+
+use actix_web::{dev::Payload, error::ErrorUnauthorized, FromRequest, HttpMessage, HttpRequest};
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use rand::RngCore;
+use serde::Deserialize;
+
+use crate::{store::Store, Result};
+
+/// How long a sign-in lasts when `IFDOHTEM_SESSION_TTL` is not set.
+pub const DEFAULT_SESSION_TTL_SECS: u64 = 8 * 60 * 60;
+
+/// `IFDOHTEM_SESSION_TTL` (seconds), defaulting to
+/// [`DEFAULT_SESSION_TTL_SECS`].
+pub fn session_ttl_from_env() -> u64 {
+    std::env::var("IFDOHTEM_SESSION_TTL")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_SESSION_TTL_SECS)
+}
+
+/// What a user may do. Roles add up, but none lifts the rule that a
+/// batch's uploader cannot approve it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Uploads batches, dry runs them and submits them for approval.
+    Uploader,
+    /// Reviews submitted batches, and approves (so runs) or rejects them.
+    Approver,
+    /// Reads batches, their history and their reports.
+    Auditor,
+    /// Assigns roles, and resumes or abandons interrupted batches.
+    Admin,
+}
+
+impl Role {
+    pub const ALL: [Role; 4] = [Role::Uploader, Role::Approver, Role::Auditor, Role::Admin];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Uploader => "uploader",
+            Role::Approver => "approver",
+            Role::Auditor => "auditor",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|r| r.as_str() == s)
+    }
+}
+
+/// The signed in user of a request. Only routes behind the sign-in check
+/// have one; extracting it anywhere else is a 401.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    pub name: String,
+    pub roles: Vec<Role>,
+}
+
+impl User {
+    pub fn has(&self, role: Role) -> bool {
+        self.roles.contains(&role)
+    }
+
+    pub fn has_any(&self, roles: &[Role]) -> bool {
+        roles.iter().any(|&role| self.has(role))
+    }
+}
+
+impl FromRequest for User {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = std::result::Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let user = req.extensions().get::<User>().cloned();
+        Box::pin(async move { user.ok_or_else(|| ErrorUnauthorized("not signed in")) })
+    }
+}
+
+/// An Argon2id hash of `password`, in the PHC string format
+/// (`$argon2id$v=19$...`) the users file and the `users` table hold.
+pub fn hash_password(password: &str) -> String {
+    let mut salt = [0; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let salt = SaltString::encode_b64(&salt).expect("16 bytes is a valid salt");
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("default Argon2 parameters hash any password")
+        .to_string()
+}
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileUser {
+    password_hash: String,
+    #[serde(default)]
+    roles: Vec<Role>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UsersFile {
+    #[serde(default)]
+    users: BTreeMap<String, FileUser>,
+}
+
+/// Who can sign in: the users of the TOML file at `IFDOHTEM_USERS`,
+///
+/// ```toml
+/// [users.alice]
+/// password_hash = "$argon2id$v=19$m=19456,t=2,p=1$..."
+/// roles = ["uploader"]
+/// ```
+///
+/// then those of the ledger's `users` table (see `ifdohtem add-user`),
+/// with their roles in `user_roles`. A name in the file shadows the same
+/// name in the table, roles included.
+#[derive(Debug, Default)]
+pub struct Users {
+    file: BTreeMap<String, FileUser>,
+}
+
+impl Users {
+    pub fn parse(toml: &str) -> std::result::Result<Self, String> {
+        let file: UsersFile = toml::from_str(toml).map_err(|e| e.to_string())?;
+        for (name, user) in &file.users {
+            if PasswordHash::new(&user.password_hash).is_err() {
+                return Err(format!("password_hash of {name:?} is not a PHC string"));
+            }
+        }
+        Ok(Self { file: file.users })
+    }
+
+    pub fn load(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        Self::parse(&std::fs::read_to_string(path)?).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{}: {e}", path.display()),
+            )
+        })
+    }
+
+    /// The file at `IFDOHTEM_USERS`, if set; the ledger's users only
+    /// otherwise.
+    pub fn from_env() -> std::io::Result<Self> {
+        match std::env::var("IFDOHTEM_USERS") {
+            Ok(path) => Self::load(path),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn file_len(&self) -> usize {
+        self.file.len()
+    }
+
+    /// Whether `name` comes from the users file, so its roles cannot be
+    /// changed from the ledger.
+    pub fn in_file(&self, name: &str) -> bool {
+        self.file.contains_key(name)
+    }
+
+    /// User `name` with their current roles, as signed in.
+    pub fn user(&self, store: &Store, name: &str) -> Result<User> {
+        let roles = match self.file.get(name) {
+            Some(user) => user.roles.clone(),
+            None => store
+                .roles(name)?
+                .iter()
+                .filter_map(|r| Role::parse(r))
+                .collect(),
+        };
+        Ok(User {
+            name: name.to_string(),
+            roles,
+        })
+    }
+
+    /// The user `name`, if `password` is theirs. Takes as long for an
+    /// unknown name, so names cannot be probed.
+    pub fn authenticate(&self, store: &Store, name: &str, password: &str) -> Result<Option<User>> {
+        let hash = match self.file.get(name) {
+            Some(user) => Some(user.password_hash.clone()),
+            None => store.password_hash(name)?,
+        };
+        let verified = match &hash {
+            Some(hash) => verify_password(password, hash),
+            None => {
+                verify_password(password, unknown_user_hash());
+                false
+            }
+        };
+        match verified {
+            true => self.user(store, name).map(Some),
+            false => Ok(None),
+        }
+    }
+}
+
+/// Verified against for names nobody has.
+fn unknown_user_hash() -> &'static str {
+    static HASH: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+    HASH.get_or_init(|| hash_password("unknown user"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authenticate() {
+        let hash = hash_password("correct horse");
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("battery staple", &hash));
+        assert!(!verify_password("correct horse", "not a hash"));
+
+        let users = Users::parse(&format!(
+            "[users.alice]\npassword_hash = {hash:?}\nroles = [\"approver\", \"auditor\"]\n"
+        ))
+        .unwrap();
+        assert!(Users::parse("[users.bob]\npassword_hash = \"hunter2\"\n").is_err());
+        assert!(Users::parse(&format!(
+            "[users.bob]\npassword_hash = {hash:?}\nroles = [\"root\"]\n"
+        ))
+        .is_err());
+        let store = Store::open_in_memory().unwrap();
+        store.add_user("carol", &hash_password("carol's")).unwrap();
+        store.set_roles("carol", &["uploader", "unknown"]).unwrap();
+        // the file wins
+        store.set_roles("alice", &["admin"]).unwrap();
+
+        let signed_in = |name: &str, password: &str| {
+            users
+                .authenticate(&store, name, password)
+                .unwrap()
+                .map(|u| u.name)
+        };
+        assert_eq!(
+            signed_in("alice", "correct horse").as_deref(),
+            Some("alice")
+        );
+        assert_eq!(signed_in("alice", "carol's"), None);
+        assert_eq!(signed_in("carol", "carol's").as_deref(), Some("carol"));
+        assert_eq!(signed_in("dave", "correct horse"), None);
+
+        let alice = users.user(&store, "alice").unwrap();
+        assert_eq!(alice.roles, [Role::Approver, Role::Auditor]);
+        assert!(alice.has_any(&[Role::Uploader, Role::Auditor]));
+        assert!(!alice.has(Role::Admin));
+        let carol = users.user(&store, "carol").unwrap();
+        assert_eq!(carol.roles, [Role::Uploader]);
+        assert!(users.user(&store, "dave").unwrap().roles.is_empty());
+    }
+}
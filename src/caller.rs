@@ -1,10 +1,18 @@
 #![doc = r"caller wrap all api call"]
 
-use std::io::{Error, ErrorKind};
+use std::{io::ErrorKind, sync::Arc, time::SystemTime};
 
-use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
+use reqwest::{Method, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
 
+use tracing::warn;
+
+use crate::error::{Error, Result};
+use crate::money::Money;
+use crate::rate_limit::{self, RateLimiter};
+use crate::retry::RetryPolicy;
+use crate::validation::to_e164;
 use crate::xml_parser::Row;
 
 // enum Entity {
@@ -18,7 +26,9 @@ struct IndividualEntity {
     branch_id: String,
     first_name: String,
     last_name: String,
+    /// E.164, e.g. `+15121231111`.
     phone: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     email: Option<String>,
     dob: String,
     #[serde(skip_serializing)]
@@ -26,7 +36,7 @@ struct IndividualEntity {
 }
 
 impl IndividualEntity {
-    fn to_api_request_json(&self) -> Result<String, serde_json::Error> {
+    fn to_api_request_json(&self) -> serde_json::Result<String> {
         let mut obj = serde_json::Map::new();
         obj.insert("type".to_string(), "individual".into());
         obj.insert("individual".to_string(), json!(self));
@@ -41,19 +51,18 @@ struct CorporationEntity {
     name: String,
     dba: String,
     ein: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     owners: Option<Vec<IndividualEntity>>,
     #[serde(skip_serializing)]
     address: Address,
 }
 
 impl CorporationEntity {
-    fn to_api_request_json(&self) -> Result<String, serde_json::Error> {
+    fn to_api_request_json(&self) -> serde_json::Result<String> {
         let mut obj = serde_json::Map::new();
         obj.insert("type".to_string(), "corporation".into());
         obj.insert("corporation".to_string(), json!(self));
         obj.insert("address".to_string(), json!(self.address));
-        obj["corporation"]["owners"] = json!([{"phone": "15121231111",}]);
-
         serde_json::to_string(&obj)
     }
 }
@@ -67,10 +76,10 @@ struct AccountEntity {
 }
 
 impl AccountEntity {
-    fn to_api_request_json(&self, holder_id: &str) -> Result<String, serde_json::Error> {
+    fn to_api_request_json(&self, holder_id: &str) -> serde_json::Result<String> {
         let mut obj = serde_json::Map::new();
         obj.insert("holder_id".to_string(), holder_id.into());
-        if self.abarouting == "" {
+        if self.abarouting.is_empty() {
             obj.insert("liability".to_string(), json!(self));
         } else {
             obj.insert("ach".to_string(), json!(self));
@@ -89,20 +98,209 @@ struct Address {
     zip: String,
 }
 
-fn row_to_individual_entity(row: &Row) -> Result<IndividualEntity, Box<dyn std::error::Error>> {
+/// Envelope every Method response is wrapped in.
+#[derive(Deserialize, Debug)]
+struct MethodResponse<T> {
+    #[allow(dead_code)]
+    success: bool,
+    data: Option<T>,
+    message: Option<String>,
+}
+
+/// `data` of a failed response.
+#[derive(Deserialize, Debug)]
+struct ErrorData {
+    error: Option<MethodError>,
+}
+
+/// The `error` object Method attaches to failed objects and responses.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MethodError {
+    #[serde(rename = "type")]
+    pub error_type: String,
+    pub sub_type: Option<String>,
+    pub code: Option<u32>,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EntityStatus {
+    Active,
+    Incomplete,
+    Disabled,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Entity {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub entity_type: String,
+    pub status: EntityStatus,
+    pub error: Option<MethodError>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountStatus {
+    Active,
+    Processing,
+    Disabled,
+    Closed,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    pub id: String,
+    pub holder_id: String,
+    #[serde(rename = "type")]
+    pub account_type: String,
+    pub status: AccountStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub liability: Option<Liability>,
+    pub error: Option<MethodError>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+/// `liability` of a liability account.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Liability {
+    pub mch_id: String,
+    /// Last digits of the account number, when Method knows them.
+    pub mask: Option<String>,
+}
+
+/// A lender Method can pay, e.g. a student loan servicer.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Merchant {
+    pub mch_id: String,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectStatus {
+    Pending,
+    InProgress,
+    Completed,
+    Failed,
+    #[serde(other)]
+    Unknown,
+}
+
+/// A Method Connect run, discovering the liabilities of an entity.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Connect {
+    pub id: String,
+    pub entity_id: String,
+    pub status: ConnectStatus,
+    #[serde(default)]
+    pub error: Option<MethodError>,
+}
+
+impl Connect {
+    /// Whether every liability account of the entity is known: `Ok(false)`
+    /// while Connect still runs, the failure if it failed.
+    pub fn completed(&self) -> Result<bool> {
+        match self.status {
+            ConnectStatus::Completed => Ok(true),
+            ConnectStatus::Failed => Err(Error::validation(
+                "liability account",
+                format!(
+                    "Method Connect failed for {}: {}",
+                    self.entity_id,
+                    self.error
+                        .as_ref()
+                        .map_or("no error detail", |e| e.message.as_str())
+                ),
+            )),
+            ConnectStatus::Pending | ConnectStatus::InProgress | ConnectStatus::Unknown => {
+                Ok(false)
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentStatus {
+    Pending,
+    Processing,
+    Sent,
+    Posted,
+    Settled,
+    Canceled,
+    Failed,
+    Reversed,
+    ReversalRequired,
+    ReversalProcessing,
+    #[serde(other)]
+    Unknown,
+}
+
+impl PaymentStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaymentStatus::Pending => "pending",
+            PaymentStatus::Processing => "processing",
+            PaymentStatus::Sent => "sent",
+            PaymentStatus::Posted => "posted",
+            PaymentStatus::Settled => "settled",
+            PaymentStatus::Canceled => "canceled",
+            PaymentStatus::Failed => "failed",
+            PaymentStatus::Reversed => "reversed",
+            PaymentStatus::ReversalRequired => "reversal_required",
+            PaymentStatus::ReversalProcessing => "reversal_processing",
+            PaymentStatus::Unknown => "unknown",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Payment {
+    pub id: String,
+    pub source: String,
+    pub destination: String,
+    /// Method sends integer cents.
+    #[serde(with = "crate::money::usd_cents")]
+    pub amount: Money,
+    pub description: String,
+    pub status: PaymentStatus,
+    pub error: Option<MethodError>,
+    pub estimated_completion_date: Option<String>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+fn row_to_individual_entity(row: &Row) -> Result<IndividualEntity> {
+    let phone = to_e164(&row.employee.phone_number)
+        .map_err(|reason| Error::validation("phone number", reason))?;
     Ok(IndividualEntity {
         id: row.employee.dunkin_id.clone(),
         branch_id: row.employee.dunkin_branch.clone(),
         first_name: row.employee.first_name.clone(),
         last_name: row.employee.last_name.clone(),
-        phone: "15121231111".to_string(),
-        email: None,
+        phone,
+        email: row
+            .employee
+            .email
+            .as_deref()
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+            .map(String::from),
         dob: row.employee.dob.clone(),
         address: None,
     })
 }
 
-fn row_to_corporation_entity(row: &Row) -> Result<CorporationEntity, Box<dyn std::error::Error>> {
+fn row_to_corporation_entity(row: &Row) -> Result<CorporationEntity> {
     Ok(CorporationEntity {
         id: row.payor.dunkin_id.clone(),
         name: row.payor.name.clone(),
@@ -119,7 +317,13 @@ fn row_to_corporation_entity(row: &Row) -> Result<CorporationEntity, Box<dyn std
     })
 }
 
-fn row_to_account_entity(row: &Row) -> Result<AccountEntity, Box<dyn std::error::Error>> {
+fn row_to_account_entity(row: &Row) -> Result<AccountEntity> {
+    if !crate::aba::is_valid_routing(&row.payor.abarouting) {
+        return Err(Error::validation(
+            "routing number",
+            format!("{:?} fails the ABA checksum", row.payor.abarouting),
+        ));
+    }
     Ok(AccountEntity {
         abarouting: row.payor.abarouting.clone(),
         account_number: row.payor.account_number.clone(),
@@ -127,127 +331,575 @@ fn row_to_account_entity(row: &Row) -> Result<AccountEntity, Box<dyn std::error:
     })
 }
 
-fn row_to_liability_entity(row: &Row) -> Result<AccountEntity, Box<dyn std::error::Error>> {
-    Ok(AccountEntity {
-        abarouting: String::new(),
-        account_number: row.payee.account_number.clone(),
-        account_type: "loan".to_string(),
-    })
+/// The account of `accounts` at one of `merchants` for loan `number`: the
+/// one whose mask `number` ends with, else the only one without a mask.
+fn match_liability<'a>(
+    accounts: &'a [Account],
+    merchants: &[Merchant],
+    number: &str,
+) -> Option<&'a Account> {
+    let number: String = number.chars().filter(char::is_ascii_alphanumeric).collect();
+    let candidates: Vec<_> = accounts
+        .iter()
+        .filter(|a| a.status != AccountStatus::Closed && a.status != AccountStatus::Disabled)
+        .filter_map(|a| Some((a, a.liability.as_ref()?)))
+        .filter(|(_, l)| merchants.iter().any(|m| m.mch_id == l.mch_id))
+        .collect();
+
+    let masked = candidates.iter().find(|(_, l)| {
+        l.mask
+            .as_deref()
+            .is_some_and(|mask| !mask.is_empty() && number.ends_with(mask))
+    });
+    if let Some((account, _)) = masked {
+        return Some(account);
+    }
+    match candidates
+        .iter()
+        .filter(|(_, l)| l.mask.is_none())
+        .collect::<Vec<_>>()[..]
+    {
+        [(account, _)] => Some(account),
+        _ => None,
+    }
 }
 
-fn row_to_amount(row: &Row) -> Result<f64, Box<dyn std::error::Error>> {
-    Ok(row.amount[1..].parse::<f64>()?)
+fn row_to_amount(row: &Row) -> Result<Money> {
+    if !row.amount.is_positive() {
+        return Err(Error::validation(
+            "amount",
+            format!("{} is zero", row.amount),
+        ));
+    }
+    Ok(row.amount)
 }
 
-const TOKEN: &'static str = include_str!("../data/methodfi-api");
+/// Method production API.
+pub const PRODUCTION_URL: &str = "https://production.methodfi.com";
+/// Method sandbox API.
+pub const SANDBOX_URL: &str = "https://sandbox.methodfi.com";
+/// Method dev API.
+pub const DEV_URL: &str = "https://dev.methodfi.com";
 
-pub async fn make_new_individual_entity(row: &Row) -> Result<Value, Box<dyn std::error::Error>> {
-    let indvidual = row_to_individual_entity(row)?;
-    let client = reqwest::Client::new();
+/// `Method-Version` header sent when none is configured.
+pub const DEFAULT_API_VERSION: &str = "2024-04-04";
 
-    let response = client
-        .post("https://production.methodfi.com/entities")
-        .header("Method-Version", "2024-04-04")
-        .header(
-            reqwest::header::AUTHORIZATION,
-            "Bearer ".to_string() + TOKEN,
-        )
-        .header(reqwest::header::CONTENT_TYPE, "application/json")
-        .json(&indvidual.to_api_request_json()?)
-        .send()
-        .await?;
-
-    Ok(json!(*response.text().await?))
-}
-
-pub async fn make_new_corporation_entity(row: &Row) -> Result<Value, Box<dyn std::error::Error>> {
-    let corporation = row_to_corporation_entity(row)?;
-    let client = reqwest::Client::new();
-    let response = client
-        .post("https://production.methodfi.com/entities")
-        .header("Method-Version", "2024-04-04")
-        .header(
-            reqwest::header::AUTHORIZATION,
-            "Bearer ".to_string() + TOKEN,
+/// Default token location, relative to the working directory.
+pub const DEFAULT_TOKEN_PATH: &str = "data/methodfi-api";
+
+/// Client for the Method API.
+///
+/// Owns a pooled `reqwest::Client`, so build it once and share it
+/// (it is cheap to clone).
+#[derive(Debug, Clone)]
+pub struct MethodClient {
+    http: reqwest::Client,
+    base_url: String,
+    api_version: String,
+    token: String,
+    retry: RetryPolicy,
+    limiter: Arc<RateLimiter>,
+    /// Answer every request locally instead of calling Method.
+    simulated: bool,
+}
+
+/// Deterministic `Idempotency-Key` for one step of one row of a batch.
+///
+/// Method replays the original response for a repeated key, so retrying
+/// (or re-running the batch after a restart) never creates a second object.
+pub fn idempotency_key(batch_id: &str, row: usize, step: &str) -> String {
+    uuid::Uuid::new_v5(
+        &uuid::Uuid::NAMESPACE_OID,
+        format!("{batch_id}/{row}/{step}").as_bytes(),
+    )
+    .to_string()
+}
+
+impl MethodClient {
+    pub fn new(base_url: impl Into<String>, token: impl Into<String>) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            api_version: DEFAULT_API_VERSION.to_string(),
+            token: token.into().trim().to_string(),
+            retry: RetryPolicy::default(),
+            limiter: RateLimiter::global(),
+            simulated: false,
+        }
+    }
+
+    /// A copy of this client that never reaches Method: every request is
+    /// still built from the row, then answered with a made up success.
+    pub fn simulated(&self) -> Self {
+        Self {
+            simulated: true,
+            ..self.clone()
+        }
+    }
+
+    pub fn is_simulated(&self) -> bool {
+        self.simulated
+    }
+
+    /// Use `limiter` instead of the process wide one.
+    pub fn with_rate_limiter(mut self, limiter: Arc<RateLimiter>) -> Self {
+        self.limiter = limiter;
+        self
+    }
+
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.limiter
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn with_api_version(mut self, api_version: impl Into<String>) -> Self {
+        self.api_version = api_version.into();
+        self
+    }
+
+    /// Build the client from the environment:
+    ///
+    /// - `METHOD_ENV`: `production` (default), `sandbox` or `dev`
+    /// - `METHOD_BASE_URL`: overrides `METHOD_ENV`, e.g. a local mock
+    /// - `METHOD_API_VERSION`: defaults to [`DEFAULT_API_VERSION`]
+    /// - `METHOD_TOKEN`, or the file at `METHOD_TOKEN_FILE`
+    ///   (defaults to [`DEFAULT_TOKEN_PATH`])
+    pub fn from_env() -> std::io::Result<Self> {
+        let base_url = match std::env::var("METHOD_BASE_URL") {
+            Ok(url) => url,
+            Err(_) => match std::env::var("METHOD_ENV").as_deref() {
+                Ok("production") | Err(_) => PRODUCTION_URL.to_string(),
+                Ok("sandbox") => SANDBOX_URL.to_string(),
+                Ok("dev") => DEV_URL.to_string(),
+                Ok(other) => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("unknown METHOD_ENV {other}"),
+                    ))
+                }
+            },
+        };
+
+        let token = match std::env::var("METHOD_TOKEN") {
+            Ok(token) => token,
+            Err(_) => std::fs::read_to_string(
+                std::env::var("METHOD_TOKEN_FILE")
+                    .unwrap_or_else(|_| DEFAULT_TOKEN_PATH.to_string()),
+            )?,
+        };
+
+        let client = Self::new(base_url, token);
+        Ok(match std::env::var("METHOD_API_VERSION") {
+            Ok(v) => client.with_api_version(v),
+            Err(_) => client,
+        })
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Which Method environment created an object: the base URL, or
+    /// `simulated`. Ids from one are meaningless in another.
+    pub fn scope(&self) -> &str {
+        match self.simulated {
+            true => "simulated",
+            false => &self.base_url,
+        }
+    }
+
+    pub fn api_version(&self) -> &str {
+        &self.api_version
+    }
+
+    /// POST `body` to `path`, retrying retryable failures per the
+    /// client's [`RetryPolicy`]. Every attempt carries the same
+    /// `idempotency_key`.
+    async fn post<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: String,
+        idempotency_key: &str,
+    ) -> Result<T> {
+        self.send(Method::POST, path, &[], Some((body, idempotency_key)))
+            .await
+    }
+
+    /// GET `path` with `query`, retried like [`post`](Self::post).
+    async fn get<T: DeserializeOwned>(&self, path: &str, query: &[(&str, &str)]) -> Result<T> {
+        self.send(Method::GET, path, query, None).await
+    }
+
+    async fn send<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        query: &[(&str, &str)],
+        body: Option<(String, &str)>,
+    ) -> Result<T> {
+        let mut attempt = 1;
+        loop {
+            match self
+                .send_once(method.clone(), path, query, body.clone())
+                .await
+            {
+                Err(e) if e.is_retryable() && attempt < self.retry.max_attempts => {
+                    let wait = match &e {
+                        Error::RateLimited {
+                            retry_after: Some(retry_after),
+                        } => *retry_after,
+                        _ => self.retry.backoff(attempt),
+                    };
+                    warn!("{path} attempt {attempt} failed: {e}, retrying in {wait:?}");
+                    tokio::time::sleep(wait).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// `body` is sent with its `Idempotency-Key`.
+    async fn send_once<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        query: &[(&str, &str)],
+        body: Option<(String, &str)>,
+    ) -> Result<T> {
+        if self.simulated {
+            return Ok(serde_json::from_value(simulate(
+                &method,
+                path,
+                query,
+                body.as_ref().map(|(body, key)| (body.as_str(), *key)),
+            )?)?);
+        }
+
+        self.limiter.acquire().await;
+        let mut request = self
+            .http
+            .request(method, format!("{}{}", self.base_url, path))
+            .query(query)
+            .header("Method-Version", &self.api_version)
+            .bearer_auth(&self.token);
+        if let Some((body, idempotency_key)) = body {
+            request = request
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header("Idempotency-Key", idempotency_key)
+                .body(body);
+        }
+        let response = request.send().await?;
+
+        self.limiter.observe(response.headers());
+
+        let status = response.status();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| rate_limit::retry_after(v, SystemTime::now()));
+            if let Some(retry_after) = retry_after {
+                self.limiter.pause(retry_after);
+            }
+            return Err(Error::RateLimited { retry_after });
+        }
+
+        let text = response.text().await?;
+        if !status.is_success() {
+            let (error, message) = match serde_json::from_str::<MethodResponse<ErrorData>>(&text) {
+                Ok(body) => (body.data.and_then(|d| d.error), body.message),
+                Err(_) => (None, Some(text)),
+            };
+            return Err(Error::Api {
+                status: status.as_u16(),
+                error,
+                message,
+            });
+        }
+
+        let body: MethodResponse<T> = serde_json::from_str(&text)?;
+        body.data.ok_or_else(|| Error::Api {
+            status: status.as_u16(),
+            error: None,
+            message: body
+                .message
+                .or_else(|| Some(format!("empty response from {path}"))),
+        })
+    }
+
+    pub async fn make_new_individual_entity(
+        &self,
+        row: &Row,
+        idempotency_key: &str,
+    ) -> Result<Entity> {
+        let indvidual = row_to_individual_entity(row)?;
+        self.post(
+            "/entities",
+            indvidual.to_api_request_json()?,
+            idempotency_key,
         )
-        .header(reqwest::header::CONTENT_TYPE, "application/json")
-        .json(&corporation.to_api_request_json()?)
-        .send()
-        .await?;
-    Ok(json!(*response.text().await?))
-}
-
-pub async fn make_new_account_entity(
-    row: &Row,
-    holder_id: &str,
-) -> Result<Value, Box<dyn std::error::Error>> {
-    let account = row_to_account_entity(row)?;
-    let client = reqwest::Client::new();
-    let response = client
-        .post("https://production.methodfi.com/accounts")
-        .header("Method-Version", "2024-04-04")
-        .header(
-            reqwest::header::AUTHORIZATION,
-            "Bearer ".to_string() + TOKEN,
+        .await
+    }
+
+    pub async fn make_new_corporation_entity(
+        &self,
+        row: &Row,
+        idempotency_key: &str,
+    ) -> Result<Entity> {
+        let corporation = row_to_corporation_entity(row)?;
+        self.post(
+            "/entities",
+            corporation.to_api_request_json()?,
+            idempotency_key,
         )
-        .header(reqwest::header::CONTENT_TYPE, "application/json")
-        .json(&account.to_api_request_json(holder_id)?)
-        .send()
-        .await?;
-    Ok(json!(*response.text().await?))
-}
-
-pub async fn make_new_liability_entity(
-    row: &Row,
-    holder_id: &str,
-) -> Result<Value, Box<dyn std::error::Error>> {
-    let liability = row_to_liability_entity(row)?;
-    let client = reqwest::Client::new();
-    let response = client
-        .post("https://production.methodfi.com/accounts")
-        .header("Method-Version", "2024-04-04")
-        .header(
-            reqwest::header::AUTHORIZATION,
-            "Bearer ".to_string() + TOKEN,
+        .await
+    }
+
+    pub async fn make_new_account_entity(
+        &self,
+        row: &Row,
+        holder_id: &str,
+        idempotency_key: &str,
+    ) -> Result<Account> {
+        let account = row_to_account_entity(row)?;
+        self.post(
+            "/accounts",
+            account.to_api_request_json(holder_id)?,
+            idempotency_key,
         )
-        .header(reqwest::header::CONTENT_TYPE, "application/json")
-        .json(&liability.to_api_request_json(holder_id)?)
-        .send()
-        .await?;
-    Ok(json!(*response.text().await?))
-}
-
-pub async fn make_new_payment_entity(
-    row: &Row,
-    src: &str,
-    target: &str,
-) -> Result<Value, Box<dyn std::error::Error>> {
-    let amount = row_to_amount(row)?;
-    let client = reqwest::Client::new();
-    let response = client
-        .post("https://production.methodfi.com/payments")
-        .header("Method-Version", "2024-04-04")
-        .header(
-            reqwest::header::AUTHORIZATION,
-            "Bearer ".to_string() + TOKEN,
+        .await
+    }
+
+    /// Merchants Method maps the Plaid institution `plaid_id` to.
+    pub async fn merchants(&self, plaid_id: &str) -> Result<Vec<Merchant>> {
+        self.get("/merchants", &[("provider_id.plaid", plaid_id)])
+            .await
+    }
+
+    /// Liability accounts of `holder_id` at `merchant`.
+    pub async fn liability_accounts(
+        &self,
+        holder_id: &str,
+        merchant: &str,
+    ) -> Result<Vec<Account>> {
+        self.get(
+            "/accounts",
+            &[
+                ("holder_id", holder_id),
+                ("type", "liability"),
+                ("liability.mch_id", merchant),
+            ],
         )
-        .header(reqwest::header::CONTENT_TYPE, "application/json")
-        .json(
-            json!(
-                {"amount": amount,
+        .await
+    }
+
+    /// Run Method Connect on `holder_id` and wait for it to complete,
+    /// checking back per the client's [`RetryPolicy`]. Fails with
+    /// [`Error::NotReady`] if it is still running after the last attempt.
+    pub async fn connect(&self, holder_id: &str, idempotency_key: &str) -> Result<Connect> {
+        let mut connect: Connect = self
+            .post(
+                &format!("/entities/{holder_id}/connect"),
+                "{}".to_string(),
+                idempotency_key,
+            )
+            .await?;
+        let mut attempt = 1;
+        while !connect.completed()? {
+            if attempt >= self.retry.max_attempts {
+                return Err(Error::NotReady(format!(
+                    "Method Connect {} of {holder_id}",
+                    connect.id
+                )));
+            }
+            tokio::time::sleep(self.retry.backoff(attempt)).await;
+            connect = self
+                .get(
+                    &format!("/entities/{holder_id}/connect/{}", connect.id),
+                    &[],
+                )
+                .await?;
+            attempt += 1;
+        }
+        Ok(connect)
+    }
+
+    /// Find the employee's account for the row's loan, since creating
+    /// liability accounts directly is not allowed: look the payee's Plaid id
+    /// up among Method's merchants, then the liability accounts `holder_id`
+    /// has at them, matched by the loan account number. If none matches
+    /// yet, Method Connect discovers the entity's liabilities and, once it
+    /// has completed, they are searched again.
+    pub async fn resolve_liability_account(
+        &self,
+        row: &Row,
+        holder_id: &str,
+        idempotency_key: &str,
+    ) -> Result<Account> {
+        let plaid_id = row.payee.plaid_id.trim();
+        let merchants = self.merchants(plaid_id).await?;
+        if merchants.is_empty() {
+            return Err(Error::validation(
+                "payee",
+                format!("no Method merchant matches Plaid id {plaid_id:?}"),
+            ));
+        }
+
+        let number = &row.payee.account_number;
+        for connected in [false, true] {
+            if connected {
+                self.connect(holder_id, idempotency_key).await?;
+            }
+            let mut accounts = Vec::new();
+            for merchant in &merchants {
+                accounts.extend(self.liability_accounts(holder_id, &merchant.mch_id).await?);
+            }
+            if let Some(account) = match_liability(&accounts, &merchants, number) {
+                return Ok(account.clone());
+            }
+        }
+
+        let names: Vec<_> = merchants.iter().map(|m| m.name.as_str()).collect();
+        let tail: String = {
+            let chars: Vec<_> = number.chars().collect();
+            chars[chars.len().saturating_sub(4)..].iter().collect()
+        };
+        Err(Error::validation(
+            "liability account",
+            format!(
+                "employee has no {} loan ending in {tail} in Method",
+                names.join(" or ")
+            ),
+        ))
+    }
+
+    pub async fn make_new_payment_entity(
+        &self,
+        row: &Row,
+        src: &str,
+        target: &str,
+        idempotency_key: &str,
+    ) -> Result<Payment> {
+        let amount = row_to_amount(row)?;
+        self.post(
+            "/payments",
+            serde_json::to_string(&json!(
+                {"amount": amount.cents(),
                  "source": src,
                  "destination": target,
                  "description": ""}
-            )
-            .as_str()
-            .ok_or(Error::new(
-                ErrorKind::Other,
-                "cannot get the corporation id",
             ))?,
+            idempotency_key,
         )
-        .send()
-        .await?;
-    Ok(json!(*response.text().await?))
+        .await
+    }
+}
+
+/// The `data` Method would answer a request with, for a dry run. Every
+/// payee matches a merchant and has one liability account there.
+fn simulate(
+    method: &Method,
+    path: &str,
+    query: &[(&str, &str)],
+    body: Option<(&str, &str)>,
+) -> Result<serde_json::Value> {
+    let now = "1970-01-01T00:00:00.000Z";
+    let sim_id = |prefix: &str, seed: &str| {
+        format!(
+            "{prefix}_sim_{}",
+            &uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_OID, seed.as_bytes())
+                .simple()
+                .to_string()[..12]
+        )
+    };
+    let param = |name: &str| {
+        query
+            .iter()
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| *v)
+            .unwrap_or_default()
+    };
+
+    let Some((body, idempotency_key)) = body.filter(|_| method == Method::POST) else {
+        return Ok(match path {
+            "/merchants" => json!([{
+                "mch_id": sim_id("mch", param("provider_id.plaid")),
+                "name": "Simulated Lender",
+            }]),
+            "/accounts" => json!([{
+                "id": sim_id("acc", &format!("{}/{}", param("holder_id"), param("liability.mch_id"))),
+                "holder_id": param("holder_id"),
+                "type": "liability",
+                "status": "active",
+                "liability": { "mch_id": param("liability.mch_id"), "mask": null },
+                "error": null,
+                "created_at": now,
+                "updated_at": now,
+            }]),
+            _ => {
+                return Err(Error::validation(
+                    "path",
+                    format!("no simulated response for GET {path}"),
+                ))
+            }
+        });
+    };
+    let body: serde_json::Value = serde_json::from_str(body)?;
+    let id = |prefix: &str| sim_id(prefix, idempotency_key);
+
+    if let Some(entity) = path
+        .strip_prefix("/entities/")
+        .and_then(|p| p.strip_suffix("/connect"))
+    {
+        return Ok(json!({
+            "id": id("cxn"),
+            "entity_id": entity,
+            "status": "completed",
+        }));
+    }
+    Ok(match path {
+        "/entities" => json!({
+            "id": id("ent"),
+            "type": body["type"],
+            "status": "active",
+            "error": null,
+            "created_at": now,
+            "updated_at": now,
+        }),
+        "/accounts" => json!({
+            "id": id("acc"),
+            "holder_id": body["holder_id"],
+            "type": if body.get("ach").is_some() { "ach" } else { "liability" },
+            "status": "active",
+            "error": null,
+            "created_at": now,
+            "updated_at": now,
+        }),
+        "/payments" => json!({
+            "id": id("pmt"),
+            "source": body["source"],
+            "destination": body["destination"],
+            "amount": body["amount"],
+            "description": body["description"],
+            "status": "pending",
+            "error": null,
+            "estimated_completion_date": null,
+            "created_at": now,
+            "updated_at": now,
+        }),
+        _ => {
+            return Err(Error::validation(
+                "path",
+                format!("no simulated response for {path}"),
+            ))
+        }
+    })
 }
 
 #[cfg(test)]
@@ -268,9 +920,216 @@ mod tests {
         //dbg!(obj);
 
         //dbg!(json!({"type": "corporation", "corporation": serde_json::to_string(&v)}))
-        dbg!(v.to_api_request_json());
+        dbg!(v.to_api_request_json()).unwrap();
 
         let v: AccountEntity = default::Default::default();
-        dbg!(v.to_api_request_json(&uuid::Uuid::new_v4().to_string()));
+        dbg!(v.to_api_request_json(&uuid::Uuid::new_v4().to_string())).unwrap();
+    }
+
+    #[test]
+    fn test_entities_use_row_contact() {
+        let buf = std::fs::read_to_string("data/onerow.xml").unwrap();
+        let mut row = crate::xml_parser::parse_xml(&buf).unwrap().row.remove(0);
+
+        let json: serde_json::Value = serde_json::from_str(
+            &row_to_individual_entity(&row)
+                .unwrap()
+                .to_api_request_json()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(json["individual"]["phone"], "+16366718717");
+        assert!(json["individual"].get("email").is_none());
+
+        let json: serde_json::Value = serde_json::from_str(
+            &row_to_corporation_entity(&row)
+                .unwrap()
+                .to_api_request_json()
+                .unwrap(),
+        )
+        .unwrap();
+        assert!(json["corporation"].get("owners").is_none());
+
+        row.employee.email = Some(" rebekah@example.com ".to_string());
+        let entity = row_to_individual_entity(&row).unwrap();
+        assert_eq!(entity.email.as_deref(), Some("rebekah@example.com"));
+
+        row.employee.phone_number = "671-8717".to_string();
+        assert!(matches!(
+            row_to_individual_entity(&row),
+            Err(Error::Validation { .. })
+        ));
+    }
+
+    #[test]
+    fn test_client_base_url() {
+        let client = MethodClient::new("http://localhost:9000/", "token\n");
+        assert_eq!(client.base_url(), "http://localhost:9000");
+        assert_eq!(client.api_version(), DEFAULT_API_VERSION);
+        assert_eq!(client.token, "token");
+
+        let client = client.with_api_version("2025-01-01");
+        assert_eq!(client.api_version(), "2025-01-01");
+    }
+
+    #[test]
+    fn test_idempotency_key() {
+        let key = idempotency_key("batch", 7, "payment");
+        assert_eq!(key, idempotency_key("batch", 7, "payment"));
+        assert_ne!(key, idempotency_key("batch", 8, "payment"));
+        assert_ne!(key, idempotency_key("batch", 7, "ach account"));
+        assert_ne!(key, idempotency_key("other", 7, "payment"));
+    }
+
+    #[tokio::test]
+    async fn test_simulated_client() {
+        let buf = std::fs::read_to_string("data/onerow.xml").unwrap();
+        let row = crate::xml_parser::parse_xml(&buf).unwrap().row.remove(0);
+        // nothing listens there, a real request would fail
+        let client = MethodClient::new("http://127.0.0.1:1", "token").simulated();
+
+        let entity = client
+            .make_new_corporation_entity(&row, "k1")
+            .await
+            .unwrap();
+        assert_eq!(entity.entity_type, "corporation");
+        let account = client
+            .make_new_account_entity(&row, &entity.id, "k2")
+            .await
+            .unwrap();
+        assert_eq!(account.holder_id, entity.id);
+        assert_eq!(account.account_type, "ach");
+        let payment = client
+            .make_new_payment_entity(&row, &account.id, "acc_dest", "k3")
+            .await
+            .unwrap();
+        assert_eq!(payment.amount, Money::usd(434));
+        assert!(payment.id.starts_with("pmt_sim_"));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_liability_account() {
+        let buf = std::fs::read_to_string("data/onerow.xml").unwrap();
+        let row = crate::xml_parser::parse_xml(&buf).unwrap().row.remove(0);
+        let client = MethodClient::new("http://127.0.0.1:1", "token").simulated();
+
+        let account = client
+            .resolve_liability_account(&row, "ent_1", "k1")
+            .await
+            .unwrap();
+        assert_eq!(account.holder_id, "ent_1");
+        assert_eq!(account.account_type, "liability");
+        let merchants = client.merchants(&row.payee.plaid_id).await.unwrap();
+        assert_eq!(account.liability.unwrap().mch_id, merchants[0].mch_id);
+
+        let merchant = |id: &str| Merchant {
+            mch_id: id.to_string(),
+            name: id.to_string(),
+        };
+        let liability = |id: &str, mch_id: &str, mask: Option<&str>| Account {
+            id: id.to_string(),
+            holder_id: "ent_1".to_string(),
+            account_type: "liability".to_string(),
+            status: AccountStatus::Active,
+            liability: Some(Liability {
+                mch_id: mch_id.to_string(),
+                mask: mask.map(String::from),
+            }),
+            error: None,
+            created_at: String::new(),
+            updated_at: String::new(),
+        };
+        let accounts = [
+            liability("acc_1", "mch_1", Some("1111")),
+            liability("acc_2", "mch_1", Some("4321")),
+            liability("acc_3", "mch_2", Some("9876")),
+        ];
+        let matched = |accounts: &[Account], number: &str| {
+            match_liability(accounts, &[merchant("mch_1")], number).map(|a| a.id.clone())
+        };
+        assert_eq!(matched(&accounts, "87-654-321").as_deref(), Some("acc_2"));
+        // right number, other lender
+        assert_eq!(matched(&accounts, "9876"), None);
+        assert_eq!(matched(&accounts, "0000"), None);
+
+        let unmasked = [accounts[2].clone(), liability("acc_4", "mch_1", None)];
+        assert_eq!(matched(&unmasked, "0000").as_deref(), Some("acc_4"));
+        let ambiguous = [unmasked[1].clone(), liability("acc_5", "mch_1", None)];
+        assert_eq!(matched(&ambiguous, "0000"), None);
+    }
+
+    #[test]
+    fn test_connect_status() {
+        let connect = |status: &str| {
+            serde_json::from_value::<Connect>(json!({
+                "id": "cxn_1",
+                "entity_id": "ent_1",
+                "status": status,
+                "error": {"type": "INVALID_REQUEST", "message": "no credit report"},
+            }))
+            .unwrap()
+        };
+        assert!(connect("completed").completed().unwrap());
+        assert!(!connect("pending").completed().unwrap());
+        assert!(!connect("in_progress").completed().unwrap());
+        assert!(!connect("something new").completed().unwrap());
+        let e = connect("failed").completed().unwrap_err();
+        assert!(e.to_string().contains("no credit report"), "{e}");
+        assert!(!e.is_retryable());
+
+        let e = Error::NotReady("Method Connect cxn_1 of ent_1".to_string()).at_row(3, "x");
+        assert!(e.is_retryable());
+        assert!(!e.is_fatal());
+    }
+
+    #[test]
+    fn test_parse_payment_response() {
+        let body = r#"{
+            "success": true,
+            "data": {
+                "id": "pmt_rPrDPEwyCVUcm",
+                "reversal_id": null,
+                "source_trace_id": null,
+                "destination_trace_id": null,
+                "source": "acc_JMJZT6r7iHi8e",
+                "destination": "acc_AXthnzpBnxxWP",
+                "amount": 434,
+                "description": "",
+                "status": "pending",
+                "error": null,
+                "metadata": null,
+                "estimated_completion_date": "2024-04-10",
+                "created_at": "2024-04-04T16:00:00.000Z",
+                "updated_at": "2024-04-04T16:00:00.000Z"
+            },
+            "message": null
+        }"#;
+        let resp: MethodResponse<Payment> = serde_json::from_str(body).unwrap();
+        let payment = resp.data.unwrap();
+        assert_eq!(payment.amount, Money::usd(434));
+        assert_eq!(payment.status, PaymentStatus::Pending);
+        assert!(payment.error.is_none());
+
+        let body = r#"{
+            "success": false,
+            "data": {
+                "id": "ent_au22b1fbFJbp8",
+                "type": "individual",
+                "status": "incomplete",
+                "error": {
+                    "type": "INVALID_REQUEST",
+                    "sub_type": "INVALID_PHONE",
+                    "code": 400,
+                    "message": "Invalid phone number"
+                },
+                "created_at": "2024-04-04T16:00:00.000Z",
+                "updated_at": "2024-04-04T16:00:00.000Z"
+            },
+            "message": null
+        }"#;
+        let resp: MethodResponse<Entity> = serde_json::from_str(body).unwrap();
+        let entity = resp.data.unwrap();
+        assert_eq!(entity.status, EntityStatus::Incomplete);
+        assert_eq!(entity.error.unwrap().code, Some(400));
     }
 }
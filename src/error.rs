@@ -0,0 +1,173 @@
+#![doc = r"crate level error type"]
+
+use std::time::Duration;
+
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+
+use crate::caller::MethodError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Could not reach Method, or the connection broke mid request.
+    #[error("transport error: {0}")]
+    Transport(#[from] reqwest::Error),
+
+    /// Method answered with a non-2xx status.
+    #[error("Method returned {status}: {}", api_message(.error, .message))]
+    Api {
+        status: u16,
+        error: Option<MethodError>,
+        message: Option<String>,
+    },
+
+    /// Method answered 429.
+    #[error("rate limited by Method")]
+    RateLimited { retry_after: Option<Duration> },
+
+    /// Method is still working on something a row needs, e.g. Connect
+    /// discovering an entity's liabilities; running the row again later
+    /// may succeed.
+    #[error("{0} has not completed yet, try again later")]
+    NotReady(String),
+
+    /// The input data cannot be turned into a valid request.
+    #[error("invalid {field}: {reason}")]
+    Validation { field: String, reason: String },
+
+    /// Something went wrong while processing one row of a batch.
+    #[error("row {row} failed at {step}: {source}")]
+    Row {
+        row: usize,
+        step: &'static str,
+        #[source]
+        source: Box<Error>,
+    },
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+
+    #[error(transparent)]
+    Xml(#[from] quick_xml::DeError),
+
+    #[error("database error: {0}")]
+    Db(#[from] rusqlite::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+fn api_message(error: &Option<MethodError>, message: &Option<String>) -> String {
+    match (error, message) {
+        (Some(e), _) => match &e.sub_type {
+            Some(sub_type) => format!("{} ({}/{})", e.message, e.error_type, sub_type),
+            None => format!("{} ({})", e.message, e.error_type),
+        },
+        (None, Some(m)) => m.clone(),
+        (None, None) => "no error detail".to_string(),
+    }
+}
+
+impl Error {
+    pub fn validation(field: impl Into<String>, reason: impl Into<String>) -> Self {
+        Error::Validation {
+            field: field.into(),
+            reason: reason.into(),
+        }
+    }
+
+    /// Whether sending the same request again may succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Transport(e) => e.is_connect() || e.is_timeout() || e.is_request(),
+            Error::Api { status, .. } => *status >= 500,
+            Error::RateLimited { .. } | Error::NotReady(_) => true,
+            Error::Row { source, .. } => source.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// Whether every later row would fail the same way, so the batch
+    /// should stop instead of moving on to the next row.
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            Error::Api { status, .. } => *status == 401 || *status == 403,
+            Error::Io(_) | Error::Db(_) | Error::Csv(_) => true,
+            Error::Row { source, .. } => source.is_fatal(),
+            _ => false,
+        }
+    }
+
+    /// The step a row level error happened at.
+    pub fn step(&self) -> Option<&'static str> {
+        match self {
+            Error::Row { step, .. } => Some(step),
+            _ => None,
+        }
+    }
+
+    pub fn at_row(self, row: usize, step: &'static str) -> Self {
+        Error::Row {
+            row,
+            step,
+            source: Box::new(self),
+        }
+    }
+}
+
+impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Error::Validation { .. } | Error::Xml(_) | Error::Csv(_) => StatusCode::BAD_REQUEST,
+            Error::Transport(_) | Error::Api { .. } => StatusCode::BAD_GATEWAY,
+            Error::RateLimited { .. } | Error::NotReady(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Row { source, .. } => source.status_code(),
+            Error::Io(_) | Error::Json(_) | Error::Db(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .content_type("text/plain")
+            .body(self.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_error_display() {
+        let e = Error::Api {
+            status: 400,
+            error: Some(MethodError {
+                error_type: "INVALID_REQUEST".to_string(),
+                sub_type: Some("INVALID_PHONE".to_string()),
+                code: Some(400),
+                message: "Invalid phone number".to_string(),
+            }),
+            message: None,
+        }
+        .at_row(3, "individual entity");
+
+        assert_eq!(
+            e.to_string(),
+            "row 3 failed at individual entity: Method returned 400: Invalid phone number (INVALID_REQUEST/INVALID_PHONE)"
+        );
+        assert_eq!(e.status_code(), StatusCode::BAD_GATEWAY);
+        assert!(!e.is_retryable());
+
+        let e = Error::Api {
+            status: 503,
+            error: None,
+            message: None,
+        };
+        assert!(e.is_retryable());
+        assert!(Error::RateLimited { retry_after: None }.is_retryable());
+    }
+}
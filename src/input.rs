@@ -0,0 +1,726 @@
+#![doc = r"the upload formats, all read into the same `Row`"]
+
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, Read},
+    ops::Range,
+    path::Path,
+};
+
+use serde::Deserialize;
+use serde_json::{value::RawValue, Value};
+
+use crate::{
+    mapping::Mapping,
+    xml_parser::{Row, RowReader},
+    Error, Result,
+};
+
+/// Every field of a row, by its path in the XML layout. Unless
+/// [mapped](Mapping) otherwise, each is read from the element, key or
+/// column of the same name.
+pub const FIELDS: &[&str] = &[
+    "Employee/DunkinId",
+    "Employee/DunkinBranch",
+    "Employee/FirstName",
+    "Employee/LastName",
+    "Employee/DOB",
+    "Employee/PhoneNumber",
+    "Employee/Email",
+    "Payor/DunkinId",
+    "Payor/ABARouting",
+    "Payor/AccountNumber",
+    "Payor/Name",
+    "Payor/DBA",
+    "Payor/EIN",
+    "Payor/Address/Line1",
+    "Payor/Address/City",
+    "Payor/Address/State",
+    "Payor/Address/Zip",
+    "Payee/PlaidId",
+    "Payee/LoanAccountNumber",
+    "Amount",
+];
+
+/// The [`FIELDS`] a row may leave out.
+pub const OPTIONAL_FIELDS: &[&str] = &["Employee/Email"];
+
+/// A line and column in the source document, both starting at 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Default for Position {
+    fn default() -> Self {
+        Self { line: 1, column: 1 }
+    }
+}
+
+/// One field of a row, e.g. `Employee/DOB`, and where its value starts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Element {
+    pub path: String,
+    pub text: String,
+    pub position: Position,
+}
+
+/// Where a row sits in the document it was read from.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RowSource {
+    pub position: Position,
+    pub elements: Vec<Element>,
+}
+
+impl RowSource {
+    pub fn element(&self, path: &str) -> Option<&Element> {
+        self.elements.iter().find(|e| e.path == path)
+    }
+
+    /// The position of `path`, or of the row if the element is absent.
+    pub fn position_of(&self, path: &str) -> Position {
+        self.element(path).map_or(self.position, |e| e.position)
+    }
+
+    /// Build the row from the elements, the way the XML is deserialized.
+    pub fn to_row(&self) -> Result<Row> {
+        let mut root = serde_json::Map::new();
+        for element in &self.elements {
+            let mut parts = element.path.split('/').peekable();
+            let mut node = &mut root;
+            while let Some(part) = parts.next() {
+                if parts.peek().is_none() {
+                    node.insert(part.to_string(), Value::String(element.text.clone()));
+                    break;
+                }
+                let child = node
+                    .entry(part)
+                    .or_insert_with(|| Value::Object(Default::default()));
+                if !child.is_object() {
+                    *child = Value::Object(Default::default());
+                }
+                node = child.as_object_mut().expect("just made an object");
+            }
+        }
+        Ok(serde_json::from_value(Value::Object(root))?)
+    }
+}
+
+/// A stream of rows read from an upload, one at a time.
+pub trait RowStream: Iterator<Item = Result<Row>> {
+    /// The format of the document.
+    fn format(&self) -> Format;
+
+    /// How far into the document the reader is.
+    fn cursor(&self) -> Position;
+
+    /// The row last returned, or being read when an error was returned.
+    fn source(&self) -> &RowSource;
+
+    /// Whether the stream has ended, either at the end of the document or
+    /// because it cannot be read any further.
+    fn is_done(&self) -> bool;
+}
+
+impl<S: RowStream + ?Sized> RowStream for Box<S> {
+    fn format(&self) -> Format {
+        (**self).format()
+    }
+
+    fn cursor(&self) -> Position {
+        (**self).cursor()
+    }
+
+    fn source(&self) -> &RowSource {
+        (**self).source()
+    }
+
+    fn is_done(&self) -> bool {
+        (**self).is_done()
+    }
+}
+
+/// Reads the rows of one format, by the paths or columns of that format.
+pub trait SourceReader {
+    /// The format it reads.
+    fn format(&self) -> Format;
+
+    /// How far into the document the reader is.
+    fn cursor(&self) -> Position;
+
+    /// The next row, or an error if the document cannot be read any
+    /// further.
+    fn read_source(&mut self) -> Option<Result<RowSource>>;
+}
+
+/// Maps what a [`SourceReader`] reads onto [`FIELDS`] and turns it into
+/// rows.
+pub struct Rows<S> {
+    reader: S,
+    mapping: Mapping,
+    source: RowSource,
+    done: bool,
+}
+
+impl<S: SourceReader> Rows<S> {
+    pub fn new(reader: S, mapping: Mapping) -> Self {
+        Self {
+            reader,
+            mapping,
+            source: RowSource::default(),
+            done: false,
+        }
+    }
+}
+
+impl<S: SourceReader> RowStream for Rows<S> {
+    fn format(&self) -> Format {
+        self.reader.format()
+    }
+
+    fn cursor(&self) -> Position {
+        self.reader.cursor()
+    }
+
+    fn source(&self) -> &RowSource {
+        &self.source
+    }
+
+    fn is_done(&self) -> bool {
+        self.done
+    }
+}
+
+impl<S: SourceReader> Iterator for Rows<S> {
+    type Item = Result<Row>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.reader.read_source() {
+            Some(Ok(raw)) => {
+                self.source = self.mapping.apply(&raw);
+                Some(self.source.to_row())
+            }
+            Some(Err(e)) => {
+                self.done = true;
+                Some(Err(e))
+            }
+            None => {
+                self.done = true;
+                None
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Xml,
+    Csv,
+    Json,
+}
+
+impl Format {
+    /// By content type, then by file extension. `None` if neither is known.
+    pub fn detect(content_type: Option<&str>, file_name: Option<&str>) -> Option<Format> {
+        let by_type =
+            content_type.and_then(|t| match t.split(';').next().unwrap_or_default().trim() {
+                "application/xml" | "text/xml" => Some(Format::Xml),
+                "text/csv" | "application/csv" => Some(Format::Csv),
+                "application/json" => Some(Format::Json),
+                _ => None,
+            });
+        by_type.or_else(|| {
+            let extension = Path::new(file_name?).extension()?.to_str()?;
+            Format::from_extension(&extension.to_ascii_lowercase())
+        })
+    }
+
+    pub fn from_extension(extension: &str) -> Option<Format> {
+        match extension {
+            "xml" => Some(Format::Xml),
+            "csv" => Some(Format::Csv),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+
+    /// As shown to users.
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Xml => "XML",
+            Format::Csv => "CSV",
+            Format::Json => "JSON",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Xml => "xml",
+            Format::Csv => "csv",
+            Format::Json => "json",
+        }
+    }
+}
+
+/// How uploads are read, from the TOML file at `IFDOHTEM_INPUT`:
+///
+/// ```toml
+/// # the element (XML) or key (JSON) holding each row
+/// row = "payout"
+///
+/// [csv]
+/// delimiter = ";"
+///
+/// [fields."Employee/DunkinId"]
+/// source = "employee_id"
+///
+/// [fields."Employee/DOB"]
+/// source = "Employee/BirthDate"
+/// transforms = [{ date = { from = "YYYY-MM-DD", to = "MM-DD-YYYY" } }]
+/// ```
+///
+/// See [`Mapping`] for the `fields`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InputConfig {
+    #[serde(default = "default_row")]
+    pub row: String,
+    #[serde(default)]
+    pub csv: CsvConfig,
+    #[serde(default)]
+    pub fields: Mapping,
+}
+
+fn default_row() -> String {
+    "row".to_string()
+}
+
+impl Default for InputConfig {
+    fn default() -> Self {
+        Self {
+            row: default_row(),
+            csv: CsvConfig::default(),
+            fields: Mapping::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CsvConfig {
+    #[serde(default = "default_delimiter")]
+    pub delimiter: char,
+}
+
+fn default_delimiter() -> char {
+    ','
+}
+
+impl Default for CsvConfig {
+    fn default() -> Self {
+        Self {
+            delimiter: default_delimiter(),
+        }
+    }
+}
+
+impl InputConfig {
+    pub fn parse(toml: &str) -> std::io::Result<Self> {
+        let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+        let config: Self = toml::from_str(toml).map_err(|e| invalid(e.to_string()))?;
+        if !config.csv.delimiter.is_ascii() {
+            return Err(invalid(
+                "the CSV delimiter must be a single ASCII character".to_string(),
+            ));
+        }
+        config.fields.check().map_err(invalid)?;
+        Ok(config)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Load the file at `IFDOHTEM_INPUT`, if set.
+    pub fn from_env() -> std::io::Result<Self> {
+        match std::env::var("IFDOHTEM_INPUT") {
+            Ok(path) => Self::load(path),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+}
+
+/// Read `input` as `format`. Everything downstream sees the same rows
+/// whatever the format.
+pub fn open<'a, R: BufRead + 'a>(
+    format: Format,
+    input: R,
+    config: &InputConfig,
+) -> Box<dyn RowStream + 'a> {
+    let mapping = config.fields.clone();
+    match format {
+        Format::Xml => Box::new(Rows::new(RowReader::new(input, &config.row), mapping)),
+        Format::Csv => Box::new(Rows::new(CsvReader::new(input, &config.csv), mapping)),
+        Format::Json => Box::new(Rows::new(JsonReader::new(input, &config.row), mapping)),
+    }
+}
+
+/// Reads the records of a CSV file with a header line, each field by its
+/// column header. A "column" in a [`Position`] is the number of the field
+/// in the record.
+pub struct CsvReader<R: Read> {
+    reader: csv::Reader<R>,
+    headers: Vec<String>,
+    record: csv::ByteRecord,
+    header_line: usize,
+    /// With CRLF line endings the csv crate puts each record on the line
+    /// before it, which shows as the first record sharing the header's
+    /// line. Known once the first record is read.
+    line_offset: Option<usize>,
+    error: Option<Error>,
+}
+
+impl<R: Read> CsvReader<R> {
+    pub fn new(input: R, config: &CsvConfig) -> Self {
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(config.delimiter as u8)
+            .flexible(true)
+            .from_reader(input);
+
+        let (headers, header_line, error) = match reader.headers() {
+            Ok(headers) => (
+                headers.iter().map(|h| h.trim().to_string()).collect(),
+                headers.position().map_or(1, |p| p.line() as usize),
+                None,
+            ),
+            Err(e) => (Vec::new(), 1, Some(e.into())),
+        };
+
+        Self {
+            reader,
+            headers,
+            record: csv::ByteRecord::new(),
+            header_line,
+            line_offset: None,
+            error,
+        }
+    }
+}
+
+impl<R: Read> SourceReader for CsvReader<R> {
+    fn format(&self) -> Format {
+        Format::Csv
+    }
+
+    fn cursor(&self) -> Position {
+        Position {
+            line: self.reader.position().line() as usize,
+            column: 1,
+        }
+    }
+
+    fn read_source(&mut self) -> Option<Result<RowSource>> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+        match self.reader.read_byte_record(&mut self.record) {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(e) => return Some(Err(e.into())),
+        }
+
+        let line = self.record.position().map_or(1, |p| p.line() as usize);
+        let line = line
+            + *self
+                .line_offset
+                .get_or_insert((line == self.header_line).into());
+        Some(Ok(RowSource {
+            position: Position { line, column: 1 },
+            elements: self
+                .headers
+                .iter()
+                .zip(self.record.iter())
+                .enumerate()
+                .map(|(index, (header, value))| Element {
+                    path: header.clone(),
+                    text: String::from_utf8_lossy(value).into_owned(),
+                    position: Position {
+                        line,
+                        column: index + 1,
+                    },
+                })
+                .collect(),
+        }))
+    }
+}
+
+/// The largest JSON document [`JsonReader`] reads, since it holds it whole;
+/// XML and CSV are read row by row, whatever their size.
+pub const MAX_JSON_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Reads the rows of a JSON document: an array of row objects, or an object
+/// with such an array under the row key. Nested keys are joined into paths,
+/// e.g. `{"Employee": {"DunkinId": "..."}}` is `Employee/DunkinId`.
+///
+/// The document is read whole, up to [`MAX_JSON_BYTES`], but each row is
+/// only parsed when reached.
+pub struct JsonReader {
+    text: String,
+    /// Where each line starts, to turn offsets into positions.
+    lines: Vec<usize>,
+    rows: std::vec::IntoIter<Range<usize>>,
+    cursor: Position,
+    error: Option<Error>,
+}
+
+impl JsonReader {
+    pub fn new(input: impl Read, row_key: &str) -> Self {
+        let mut bytes = Vec::new();
+        let read = input
+            .take(MAX_JSON_BYTES + 1)
+            .read_to_end(&mut bytes)
+            .map_err(Error::from)
+            .and_then(|len| match len as u64 > MAX_JSON_BYTES {
+                true => Err(Error::validation(
+                    "JSON",
+                    format!(
+                        "is larger than {} MiB, upload a batch this large as XML or CSV",
+                        MAX_JSON_BYTES >> 20
+                    ),
+                )),
+                false => Ok(()),
+            })
+            .and_then(|_| {
+                String::from_utf8(bytes)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e).into())
+            });
+        let (text, read) = match read {
+            Ok(text) => (text, Ok(())),
+            Err(e) => (String::new(), Err(e)),
+        };
+        let lines = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+
+        let rows = read.and_then(|_| {
+            let rows = match text.trim_start().starts_with('{') {
+                true => {
+                    let mut document: BTreeMap<String, &RawValue> = serde_json::from_str(&text)?;
+                    let rows = document.remove(row_key).ok_or_else(|| {
+                        Error::validation("JSON", format!("has no {row_key:?} key"))
+                    })?;
+                    serde_json::from_str::<Vec<&RawValue>>(rows.get())?
+                }
+                false => serde_json::from_str::<Vec<&RawValue>>(&text)?,
+            };
+            Ok(rows
+                .into_iter()
+                .map(|raw| {
+                    let start = offset(&text, raw.get());
+                    start..start + raw.get().len()
+                })
+                .collect::<Vec<_>>())
+        });
+
+        let mut cursor = Position::default();
+        let (rows, error) = match rows {
+            Ok(rows) => (rows, None),
+            Err(e) => {
+                if let Error::Json(e) = &e {
+                    cursor = Position {
+                        line: e.line().max(1),
+                        column: e.column().max(1),
+                    };
+                }
+                (Vec::new(), Some(e))
+            }
+        };
+
+        Self {
+            text,
+            lines,
+            rows: rows.into_iter(),
+            cursor,
+            error,
+        }
+    }
+
+    fn position_at(&self, offset: usize) -> Position {
+        let line = self.lines.partition_point(|&start| start <= offset);
+        Position {
+            line,
+            column: offset - self.lines[line - 1] + 1,
+        }
+    }
+
+    /// Add the leaves under `raw` to `elements`, by their path.
+    fn collect_leaves(
+        &self,
+        prefix: &str,
+        raw: &RawValue,
+        elements: &mut Vec<Element>,
+    ) -> Result<()> {
+        let json = raw.get();
+        if json.starts_with('{') {
+            let object: BTreeMap<String, &RawValue> = serde_json::from_str(json)?;
+            for (key, value) in object {
+                let path = match prefix {
+                    "" => key,
+                    _ => format!("{prefix}/{key}"),
+                };
+                self.collect_leaves(&path, value, elements)?;
+            }
+            return Ok(());
+        }
+
+        let text = match serde_json::from_str::<Value>(json)? {
+            Value::Null => return Ok(()),
+            Value::String(s) => s,
+            _ => json.to_string(),
+        };
+        elements.push(Element {
+            path: prefix.to_string(),
+            text,
+            position: self.position_at(offset(&self.text, json)),
+        });
+        Ok(())
+    }
+}
+
+/// Where `part`, a slice of `text`, starts in it.
+fn offset(text: &str, part: &str) -> usize {
+    part.as_ptr() as usize - text.as_ptr() as usize
+}
+
+impl SourceReader for JsonReader {
+    fn format(&self) -> Format {
+        Format::Json
+    }
+
+    fn cursor(&self) -> Position {
+        self.cursor
+    }
+
+    fn read_source(&mut self) -> Option<Result<RowSource>> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+
+        let range = self.rows.next()?;
+        self.cursor = self.position_at(range.start);
+        let mut source = RowSource {
+            position: self.cursor,
+            elements: Vec::new(),
+        };
+        // a row that is not an object has no fields, so every one is missing
+        let leaves = serde_json::from_str::<&RawValue>(&self.text[range])
+            .map_err(Error::from)
+            .and_then(|raw| match raw.get().starts_with('{') {
+                true => self.collect_leaves("", raw, &mut source.elements),
+                false => Ok(()),
+            });
+        Some(leaves.map(|_| source))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xml_parser::parse_xml;
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(
+            Format::detect(Some("text/csv; charset=utf-8"), Some("a.xml")),
+            Some(Format::Csv)
+        );
+        assert_eq!(
+            Format::detect(Some("application/octet-stream"), Some("payouts.JSON")),
+            Some(Format::Json)
+        );
+        assert_eq!(Format::detect(None, Some("payouts.txt")), None);
+    }
+
+    #[test]
+    fn test_csv_and_json_match_xml() {
+        let buf = std::fs::read_to_string("data/onerow.xml").unwrap();
+        let expected = parse_xml(&buf).unwrap().row;
+        let row = &expected[0];
+
+        let config = InputConfig::parse(
+            r#"
+            [csv]
+            delimiter = ";"
+
+            [fields."Employee/DunkinId"]
+            source = "employee_id"
+            "#,
+        )
+        .unwrap();
+
+        // the same values under the configured headers
+        let mut xml = crate::xml_parser::stream_rows(buf.as_bytes());
+        xml.next().unwrap().unwrap();
+        let (headers, values): (Vec<_>, Vec<_>) = FIELDS
+            .iter()
+            .filter_map(|&f| {
+                let header = match f {
+                    "Employee/DunkinId" => "employee_id",
+                    _ => f,
+                };
+                Some((header, xml.source().element(f)?.text.as_str()))
+            })
+            .unzip();
+        for newline in ["\n", "\r\n"] {
+            let csv = format!(
+                "{}{newline}{}{newline}{}{newline}",
+                headers.join(";"),
+                values.join(";"),
+                values.join(";")
+            );
+            let mut rows = open(Format::Csv, csv.as_bytes(), &config);
+            assert_eq!(&rows.next().unwrap().unwrap(), row);
+            assert_eq!(&rows.next().unwrap().unwrap(), row);
+            assert_eq!(
+                rows.source().position_of("Employee/DOB"),
+                Position { line: 3, column: 5 }
+            );
+            assert!(rows.next().is_none());
+        }
+
+        let json = serde_json::to_string_pretty(&serde_json::json!({ "row": [row, row] })).unwrap();
+        let mut rows = open(Format::Json, json.as_bytes(), &InputConfig::default());
+        assert_eq!(&rows.next().unwrap().unwrap(), row);
+        let dob = rows.source().element("Employee/DOB").unwrap();
+        assert_eq!(
+            &json.lines().nth(dob.position.line - 1).unwrap()[dob.position.column - 1..],
+            "\"11-29-1997\","
+        );
+        assert_eq!(&rows.next().unwrap().unwrap(), row);
+        assert!(rows.next().is_none());
+
+        let mut rows = open(
+            Format::Json,
+            &b"[{\"Amount\": }]"[..],
+            &InputConfig::default(),
+        );
+        assert!(rows.next().unwrap().is_err());
+        assert!(rows.is_done());
+        assert_eq!(rows.cursor().column, 13);
+
+        // refused before parsing anything
+        let huge = std::io::repeat(b' ').take(MAX_JSON_BYTES + 1);
+        let mut rows = open(
+            Format::Json,
+            std::io::BufReader::new(huge),
+            &InputConfig::default(),
+        );
+        let e = rows.next().unwrap().unwrap_err();
+        assert!(e.to_string().contains("larger than 16 MiB"), "{e}");
+        assert!(rows.next().is_none());
+    }
+}
@@ -0,0 +1,331 @@
+#![doc = r"background payout jobs"]
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use serde::Serialize;
+use tracing::{error, info};
+
+use crate::{caller::MethodClient, ledger, payouts_call, store::Store, Reports, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "state", content = "reason")]
+pub enum JobState {
+    Queued,
+    Running,
+    Completed,
+    Failed(String),
+}
+
+impl JobState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Completed => "completed",
+            JobState::Failed(_) => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub id: String,
+    pub batch_id: String,
+    pub state: JobState,
+    pub total: usize,
+    pub done: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    /// Row index and step started last; other rows may be running too.
+    pub current: Option<(usize, &'static str)>,
+    /// Id the report files are named after, once the job completed.
+    pub report_id: Option<String>,
+    /// Ran against the simulated provider, nothing reached Method.
+    pub simulated: bool,
+    /// Row index and reason of every failed row, by row index.
+    pub failures: Vec<(usize, String)>,
+}
+
+impl JobStatus {
+    pub fn pending(&self) -> usize {
+        self.total - self.done - self.failed - self.skipped
+    }
+}
+
+/// Shared handle a running job reports its progress through.
+#[derive(Debug, Clone)]
+pub struct Progress(Arc<Mutex<JobStatus>>);
+
+impl Progress {
+    fn new(id: String, batch_id: String, total: usize, simulated: bool) -> Self {
+        Progress(Arc::new(Mutex::new(JobStatus {
+            id,
+            batch_id,
+            state: JobState::Queued,
+            total,
+            done: 0,
+            failed: 0,
+            skipped: 0,
+            current: None,
+            report_id: None,
+            simulated,
+            failures: Vec::new(),
+        })))
+    }
+
+    /// Progress nobody watches, for running `payouts_call` inline.
+    pub fn detached(total: usize) -> Self {
+        Self::new(String::new(), String::new(), total, false)
+    }
+
+    pub fn snapshot(&self) -> JobStatus {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn step(&self, row: usize, step: &'static str) {
+        self.0.lock().unwrap().current = Some((row, step));
+    }
+
+    pub fn row_done(&self) {
+        self.0.lock().unwrap().done += 1;
+    }
+
+    pub fn row_failed(&self, row: usize, reason: String) {
+        let mut status = self.0.lock().unwrap();
+        status.failed += 1;
+        let at = status.failures.partition_point(|(r, _)| *r < row);
+        status.failures.insert(at, (row, reason));
+    }
+
+    pub fn row_skipped(&self) {
+        self.0.lock().unwrap().skipped += 1;
+    }
+
+    fn set_state(&self, state: JobState) {
+        let mut status = self.0.lock().unwrap();
+        if state == JobState::Completed || matches!(state, JobState::Failed(_)) {
+            status.current = None;
+        }
+        status.state = state;
+    }
+}
+
+/// Every job started by this process.
+#[derive(Debug)]
+pub struct JobQueue {
+    jobs: Mutex<HashMap<String, Progress>>,
+    /// Rows each job runs at once.
+    concurrency: usize,
+}
+
+impl Default for JobQueue {
+    fn default() -> Self {
+        Self {
+            jobs: Default::default(),
+            concurrency: crate::DEFAULT_CONCURRENCY,
+        }
+    }
+}
+
+impl JobQueue {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    /// Run a batch already imported into `store` in the background,
+    /// writing the reports into `report_dir` when it finishes, whether or
+    /// not it completed. Returns the job id.
+    ///
+    /// With a [simulated](MethodClient::simulated) client this is a dry
+    /// run: hand it a throwaway store, and the report files are prefixed
+    /// with `simulated-`.
+    pub fn spawn(
+        &self,
+        client: MethodClient,
+        store: Arc<Store>,
+        batch_id: String,
+        report_dir: String,
+    ) -> Result<String> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let progress = Progress::new(
+            id.clone(),
+            batch_id.clone(),
+            store.row_count(&batch_id)?,
+            client.is_simulated(),
+        );
+        self.jobs
+            .lock()
+            .unwrap()
+            .insert(id.clone(), progress.clone());
+
+        let concurrency = self.concurrency;
+        actix_web::rt::spawn(async move {
+            progress.set_state(JobState::Running);
+            info!(
+                "job {} started for batch {}",
+                progress.snapshot().id,
+                batch_id
+            );
+
+            let (reports, stopped) =
+                match payouts_call(&client, &store, &batch_id, &progress, concurrency).await {
+                    Ok(reports) => (Ok(reports), None),
+                    // the rows it got through, and the ones it never ran
+                    Err(e) => {
+                        let reports =
+                            ledger(&store, &batch_id, |store, batch_id| store.reports(batch_id));
+                        (reports.await, Some(e))
+                    }
+                };
+            let written = reports
+                .and_then(|reports| write_reports(&report_dir, &reports, client.is_simulated()));
+
+            let failure = match (written, stopped) {
+                (Ok(report_id), stopped) => {
+                    progress.0.lock().unwrap().report_id = Some(report_id);
+                    stopped
+                }
+                (Err(e), None) => Some(e),
+                (Err(e), Some(stopped)) => {
+                    error!("could not write the reports of batch {batch_id}: {e}");
+                    Some(stopped)
+                }
+            };
+            match failure {
+                None => progress.set_state(JobState::Completed),
+                Some(e) => {
+                    error!("job for batch {batch_id} failed: {e}");
+                    progress.set_state(JobState::Failed(e.to_string()));
+                }
+            }
+        });
+
+        Ok(id)
+    }
+
+    pub fn status(&self, id: &str) -> Option<JobStatus> {
+        self.jobs.lock().unwrap().get(id).map(Progress::snapshot)
+    }
+
+    /// The jobs this process started for `batch_id`, dry runs included.
+    pub fn for_batch(&self, batch_id: &str) -> Vec<JobStatus> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .values()
+            .map(Progress::snapshot)
+            .filter(|status| status.batch_id == batch_id)
+            .collect()
+    }
+}
+
+/// Write the four reports as `{report_id}_{a,b,c,d}.csv` and return the id.
+pub fn write_reports(
+    report_dir: &str,
+    Reports(a, b, c, d): &Reports,
+    simulated: bool,
+) -> Result<String> {
+    let id = match simulated {
+        true => format!("simulated-{}", uuid::Uuid::new_v4()),
+        false => uuid::Uuid::new_v4().to_string(),
+    };
+    crate::save_btreemap_to_csv(&format!("{report_dir}/{id}_a.csv"), a)?;
+    crate::save_btreemap_to_csv(&format!("{report_dir}/{id}_b.csv"), b)?;
+    crate::save_payments_to_csv(&format!("{report_dir}/{id}_c.csv"), c)?;
+    crate::save_outcomes_to_csv(&format!("{report_dir}/{id}_d.csv"), d)?;
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress() {
+        let progress = Progress::detached(3);
+        progress.step(0, "payment");
+        progress.row_done();
+        progress.row_failed(1, "bad amount".to_string());
+
+        let status = progress.snapshot();
+        assert_eq!(status.pending(), 1);
+        assert_eq!(status.failures, vec![(1, "bad amount".to_string())]);
+        assert_eq!(status.current, Some((0, "payment")));
+
+        progress.set_state(JobState::Completed);
+        assert_eq!(progress.snapshot().current, None);
+    }
+
+    #[actix_web::test]
+    async fn test_failed_job_has_reports() {
+        let buf = std::fs::read_to_string("data/onerow.xml").unwrap();
+        let mut rows = crate::xml_parser::parse_xml(&buf).unwrap().row;
+        rows.extend(std::iter::repeat_n(rows[0].clone(), 4));
+        rows[2].employee.dunkin_id = "breaks the ledger".to_string();
+
+        let dir = std::env::temp_dir().join(format!("ifdohtem-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("ledger.db");
+        let path = path.to_str().unwrap();
+        let store = Arc::new(Store::open(path).unwrap());
+        store.create_batch("b1", &rows).unwrap();
+        // the ledger fails, fatally, under row 2
+        rusqlite::Connection::open(path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TRIGGER broken BEFORE INSERT ON shared_objects
+                 WHEN NEW.key = 'breaks the ledger'
+                 BEGIN SELECT RAISE(ABORT, 'disk I/O error'); END",
+            )
+            .unwrap();
+
+        let client = MethodClient::new("http://127.0.0.1:1", "token").simulated();
+        let jobs = JobQueue::new().with_concurrency(1);
+        let id = jobs
+            .spawn(
+                client,
+                store.clone(),
+                "b1".to_string(),
+                dir.display().to_string(),
+            )
+            .unwrap();
+        let status = loop {
+            let status = jobs.status(&id).unwrap();
+            if let JobState::Failed(_) | JobState::Completed = status.state {
+                break status;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        };
+        assert!(matches!(status.state, JobState::Failed(_)));
+
+        let report_id = status.report_id.unwrap();
+        let d = std::fs::read_to_string(dir.join(format!("{report_id}_d.csv"))).unwrap();
+        let outcomes: Vec<Vec<&str>> = d
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').take(3).collect())
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                vec!["2", "failed", "individual entity"],
+                vec!["3", "skipped", ""],
+                vec!["4", "skipped", ""],
+            ]
+        );
+        // and the rows it got through were paid
+        let c = std::fs::read_to_string(dir.join(format!("{report_id}_c.csv"))).unwrap();
+        assert_eq!(c.lines().count(), 3);
+
+        drop(store);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,206 +1,763 @@
 use std::{
-    collections::BTreeMap,
-    io::{Error, ErrorKind},
-    time::Duration,
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    sync::{Arc, Mutex, OnceLock},
 };
 
-use actix_web::rt::time;
 use csv::WriterBuilder;
-use serde_json::Value;
+use futures_util::{stream, StreamExt};
 
+pub mod aba;
+pub mod auth;
 pub mod caller;
+pub mod error;
+pub mod input;
+pub mod jobs;
+pub mod mapping;
+pub mod money;
+pub mod rate_limit;
+pub mod reports;
+pub mod retry;
+pub mod session;
+pub mod store;
+pub mod validation;
 pub mod xml_parser;
 
+pub use error::{Error, Result};
+use store::{BatchStatus, RowStatus};
+
 pub struct Reports(
-    pub BTreeMap<String, f64>,
-    pub BTreeMap<String, f64>,
-    pub Vec<Value>,
+    pub BTreeMap<String, money::Money>,
+    pub BTreeMap<String, money::Money>,
+    pub Vec<caller::Payment>,
+    pub Vec<store::RowOutcome>,
 );
 
+/// Rows run at once when `PAYOUT_CONCURRENCY` is not set.
+pub const DEFAULT_CONCURRENCY: usize = 16;
+
+/// `PAYOUT_CONCURRENCY`, defaulting to [`DEFAULT_CONCURRENCY`].
+pub fn concurrency_from_env() -> usize {
+    std::env::var("PAYOUT_CONCURRENCY")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(DEFAULT_CONCURRENCY)
+}
+
+/// Up to `concurrency` rows run at once, each through its steps in order.
+/// Rate limiting happens inside `MethodClient`, through the process wide
+/// [`rate_limit::RateLimiter`], so it holds however many rows run. Every
+/// step is written through `store`, and the reports are built from it,
+/// ordered by row, once the batch is done.
+///
+/// The rows come from the ledger one at a time, so the batch must have been
+/// [imported](store::Store::import_batch) first.
 pub async fn payouts_call(
-    rows: Vec<xml_parser::Row>,
-) -> Result<Reports, Box<dyn std::error::Error>> {
-    let mut count = 0;
-    let mut interval = time::interval(Duration::from_secs(60));
-
-    // Total amount of funds paid out per unique source account.
-    let mut report1 = BTreeMap::new();
-    // Total amount of funds paid out per Dunkin branch.
-    let mut report2 = BTreeMap::new();
-    // The status of every payment and its relevant metadata.
-    let mut report3 = vec![];
-
-    // generate all entity/account/etc.
-    for row in &rows {
-        let individual;
-        if count < 600 {
-            individual = caller::make_new_individual_entity(row).await?;
-            count += 1;
-        } else {
-            interval.tick().await;
-            count = 0;
-            individual = caller::make_new_individual_entity(row).await?;
-            count += 1;
+    client: &caller::MethodClient,
+    store: &Arc<store::Store>,
+    batch_id: &str,
+    progress: &jobs::Progress,
+    concurrency: usize,
+) -> Result<Reports> {
+    // a resumed batch was already claimed, see `Store::resume_batch`
+    ledger(store, batch_id, |store, batch_id| {
+        if store.batch_status(batch_id)? != Some(BatchStatus::Running) {
+            store.set_batch_status(batch_id, BatchStatus::Running)?;
         }
+        Ok(())
+    })
+    .await?;
 
-        let corporation;
-        if count < 600 {
-            corporation = caller::make_new_corporation_entity(row).await?;
-            count += 1;
-        } else {
-            interval.tick().await;
-            count = 0;
-            corporation = caller::make_new_corporation_entity(row).await?;
-            count += 1;
+    match payouts_rows(client, store, batch_id, progress, concurrency).await {
+        Ok(()) => {
+            ledger(store, batch_id, |store, batch_id| {
+                store.set_batch_status(batch_id, BatchStatus::Completed)?;
+                store.reports(batch_id)
+            })
+            .await
         }
-
-        //
-        let corp_account;
-        if count < 600 {
-            corp_account = caller::make_new_account_entity(
-                row,
-                corporation["id"].as_str().ok_or(Error::new(
-                    ErrorKind::Other,
-                    "cannot get the corporation id",
-                ))?,
-            )
+        Err(e) => {
+            ledger(store, batch_id, |store, batch_id| {
+                store.set_batch_status(batch_id, BatchStatus::Failed)
+            })
             .await?;
-            count += 1;
-        } else {
-            interval.tick().await;
-            count = 0;
-            corp_account = caller::make_new_account_entity(
-                row,
-                corporation["id"].as_str().ok_or(Error::new(
-                    ErrorKind::Other,
-                    "cannot get the corporation id",
-                ))?,
-            )
-            .await?;
-            count += 1;
+            Err(e)
         }
+    }
+}
 
-        let loan_account;
-        if count < 600 {
-            loan_account = caller::make_new_liability_entity(
-                row,
-                individual["id"]
-                    .as_str()
-                    .ok_or(Error::new(ErrorKind::Other, "cannot get the individual id"))?,
-            )
-            .await?;
-            count += 1;
-        } else {
-            interval.tick().await;
-            count = 0;
-            loan_account = caller::make_new_liability_entity(
-                row,
-                individual["id"]
-                    .as_str()
-                    .ok_or(Error::new(ErrorKind::Other, "cannot get the individual id"))?,
-            )
-            .await?;
-            count += 1;
+/// Run `f` on the blocking pool, with the ledger and `batch_id`. SQLite
+/// calls block, and on the runtime they would hold up every row waiting on
+/// Method, and the HTTP workers sharing it.
+pub(crate) async fn ledger<T: Send + 'static>(
+    store: &Arc<store::Store>,
+    batch_id: &str,
+    f: impl FnOnce(&store::Store, &str) -> Result<T> + Send + 'static,
+) -> Result<T> {
+    let (store, batch_id) = (store.clone(), batch_id.to_string());
+    match tokio::task::spawn_blocking(move || f(&store, &batch_id)).await {
+        Ok(result) => result,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// Run `step` of row `i`, unless the ledger already has the Method object
+/// it creates, and return that object's id. This is what makes resuming a
+/// batch safe: finished steps are never sent again.
+async fn resumable_step(
+    store: &Arc<store::Store>,
+    batch_id: &str,
+    i: usize,
+    step: &'static str,
+    progress: &jobs::Progress,
+    call: impl Future<Output = Result<String>>,
+) -> Result<String> {
+    // ledger failures are the row's too, possibly after Method acted
+    let at_row = |e: Error| e.at_row(i, step);
+    let recorded = ledger(store, batch_id, move |store, batch_id| {
+        store.object_id(batch_id, i, step)
+    });
+    if let Some(id) = recorded.await.map_err(at_row)? {
+        return Ok(id);
+    }
+
+    progress.step(i, step);
+    let id = call.await.map_err(at_row)?;
+    let record = {
+        let id = id.clone();
+        ledger(store, batch_id, move |store, batch_id| {
+            store.record_object(batch_id, i, step, &id)
+        })
+    };
+    record.await.map_err(at_row)?;
+    Ok(id)
+}
+
+/// One lock per shared object, held while it is looked up or created, so
+/// rows running at once that need the same object create it only once.
+#[derive(Default)]
+struct SharedLocks(Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>);
+
+/// Releases its key, and forgets the lock once nobody else waits for it.
+struct SharedGuard<'a> {
+    locks: &'a SharedLocks,
+    key: String,
+    lock: Arc<tokio::sync::Mutex<()>>,
+    guard: Option<tokio::sync::OwnedMutexGuard<()>>,
+}
+
+impl SharedLocks {
+    fn global() -> &'static SharedLocks {
+        static GLOBAL: OnceLock<SharedLocks> = OnceLock::new();
+        GLOBAL.get_or_init(SharedLocks::default)
+    }
+
+    async fn lock(&self, key: String) -> SharedGuard<'_> {
+        let lock = self
+            .0
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let guard = lock.clone().lock_owned().await;
+        SharedGuard {
+            locks: self,
+            key,
+            lock,
+            guard: Some(guard),
         }
+    }
+}
 
-        // payment
-        if count < 600 {
-            match caller::make_new_payment_entity(
-                row,
-                corp_account["id"].as_str().ok_or(Error::new(
-                    ErrorKind::Other,
-                    "cannot get the corp_account id",
-                ))?,
-                loan_account["id"].as_str().ok_or(Error::new(
-                    ErrorKind::Other,
-                    "cannot get the loan_account id",
-                ))?,
-            )
-            .await
-            {
-                Ok(resp) => {
-                    let en = report1
-                        .entry(corp_account["id"].as_str().unwrap().to_string())
-                        .or_insert(0_f64);
-                    *en += resp["amount"]
-                        .as_f64()
-                        .ok_or(Error::new(ErrorKind::Other, "number parsed failed"))
-                        .unwrap();
-
-                    let en = report2
-                        .entry(row.employee.dunkin_branch.clone())
-                        .or_insert(0_f64);
-                    *en += resp["amount"]
-                        .as_f64()
-                        .ok_or(Error::new(ErrorKind::Other, "number parsed failed"))
-                        .unwrap();
-
-                    report3.push(resp);
-                }
-                Err(_) => continue,
-            };
-            count += 1;
-        } else {
-            interval.tick().await;
-            count = 0;
-            match caller::make_new_payment_entity(
-                row,
-                corp_account["id"].as_str().ok_or(Error::new(
-                    ErrorKind::Other,
-                    "cannot get the corp_account id",
-                ))?,
-                loan_account["id"].as_str().ok_or(Error::new(
-                    ErrorKind::Other,
-                    "cannot get the loan_account id",
-                ))?,
-            )
-            .await
-            {
-                Ok(resp) => {
-                    let en = report1
-                        .entry(corp_account["id"].as_str().unwrap().to_string())
-                        .or_insert(0_f64);
-                    *en += resp["amount"]
-                        .as_f64()
-                        .ok_or(Error::new(ErrorKind::Other, "number parsed failed"))
-                        .unwrap();
-
-                    let en = report2
-                        .entry(row.employee.dunkin_branch.clone())
-                        .or_insert(0_f64);
-                    *en += resp["amount"]
-                        .as_f64()
-                        .ok_or(Error::new(ErrorKind::Other, "number parsed failed"))
-                        .unwrap();
-
-                    report3.push(resp);
+impl Drop for SharedGuard<'_> {
+    fn drop(&mut self) {
+        self.guard.take();
+        let mut locks = self.locks.0.lock().unwrap();
+        // the map's and ours
+        if Arc::strong_count(&self.lock) == 2 {
+            locks.remove(&self.key);
+        }
+    }
+}
+
+/// [`resumable_step`] for an object rows share: the entity of an employee
+/// or payor, or the account behind a routing/account pair. The first row,
+/// in this batch or any earlier one, to need the object creates it; every
+/// later row reuses its id.
+#[allow(clippy::too_many_arguments)]
+async fn shared_step(
+    client: &caller::MethodClient,
+    store: &Arc<store::Store>,
+    batch_id: &str,
+    i: usize,
+    step: &'static str,
+    key: &str,
+    progress: &jobs::Progress,
+    call: impl Future<Output = Result<String>>,
+) -> Result<String> {
+    let _guard = SharedLocks::global()
+        .lock(format!("{}/{step}/{key}", client.scope()))
+        .await;
+    let at_row = |e: Error| e.at_row(i, step);
+    let (scope, key) = (client.scope().to_string(), key.to_string());
+    let reused = {
+        let (scope, key) = (scope.clone(), key.clone());
+        ledger(store, batch_id, move |store, batch_id| {
+            if store.object_id(batch_id, i, step)?.is_some() {
+                return Ok(None);
+            }
+            let id = store.shared_object(&scope, step, &key)?;
+            if let Some(id) = &id {
+                store.record_object(batch_id, i, step, id)?;
+            }
+            Ok(id)
+        })
+    };
+    if let Some(id) = reused.await.map_err(at_row)? {
+        return Ok(id);
+    }
+
+    let id = resumable_step(store, batch_id, i, step, progress, call).await?;
+    let record = {
+        let id = id.clone();
+        ledger(store, batch_id, move |store, _| {
+            store.record_shared_object(&scope, step, &key, &id)
+        })
+    };
+    record.await.map_err(at_row)?;
+    Ok(id)
+}
+
+/// Every row ends as succeeded, failed at a step, or skipped. A row level
+/// failure does not affect the other rows; a [fatal](Error::is_fatal) one
+/// lets the rows already running finish, skips every row not started yet
+/// and stops the batch.
+async fn payouts_rows(
+    client: &caller::MethodClient,
+    store: &Arc<store::Store>,
+    batch_id: &str,
+    progress: &jobs::Progress,
+    concurrency: usize,
+) -> Result<()> {
+    let row_count = ledger(store, batch_id, |store, batch_id| store.row_count(batch_id)).await?;
+    let stopped: Mutex<Option<String>> = Mutex::new(None);
+
+    let mut results = stream::iter(0..row_count)
+        .map(|i| {
+            let stopped = &stopped;
+            async move {
+                let reason = stopped.lock().unwrap().clone();
+                if let Some(reason) = reason {
+                    let skipped = ledger(store, batch_id, move |store, batch_id| {
+                        if store.row_status(batch_id, i)? == Some(RowStatus::Succeeded) {
+                            return Ok(false);
+                        }
+                        store.set_row_outcome(batch_id, i, None, &reason)?;
+                        Ok(true)
+                    });
+                    match skipped.await.map_err(|e| (i, e))? {
+                        true => progress.row_skipped(),
+                        false => progress.row_done(),
+                    }
+                    return Ok(());
                 }
-                Err(_) => continue,
-            };
-            count += 1;
+                payouts_row_at(client, store, batch_id, i, progress)
+                    .await
+                    .inspect_err(|e| {
+                        stopped
+                            .lock()
+                            .unwrap()
+                            .get_or_insert_with(|| format!("batch stopped at row {i}: {e}"));
+                    })
+                    .map_err(|e| (i, e))
+            }
+        })
+        .buffer_unordered(concurrency.max(1));
+
+    // of the rows that stopped the batch, the first
+    let mut first: Option<(usize, Error)> = None;
+    while let Some(result) = results.next().await {
+        if let Err((i, e)) = result {
+            if first.as_ref().is_none_or(|(j, _)| i < *j) {
+                first = Some((i, e));
+            }
         }
     }
+    match first {
+        Some((_, e)) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Run row `i` and record how it ended. Only a [fatal](Error::is_fatal)
+/// failure, or one of the ledger, is returned.
+async fn payouts_row_at(
+    client: &caller::MethodClient,
+    store: &Arc<store::Store>,
+    batch_id: &str,
+    i: usize,
+    progress: &jobs::Progress,
+) -> Result<()> {
+    // the row to run, if it still needs running
+    let todo = ledger(store, batch_id, move |store, batch_id| {
+        if store.row_status(batch_id, i)? == Some(RowStatus::Succeeded) {
+            return Ok(None);
+        }
+        let row = store.batch_row(batch_id, i)?;
+        if row.is_some() {
+            store.set_row_status(batch_id, i, RowStatus::Running, None)?;
+        }
+        Ok(Some(row))
+    });
+    let row = match todo.await? {
+        None => {
+            progress.row_done();
+            return Ok(());
+        }
+        Some(None) => return Ok(()),
+        Some(Some(row)) => row,
+    };
 
-    Ok(Reports(report1, report2, report3))
+    match payouts_row(client, store, batch_id, i, &row, progress).await {
+        Ok(()) => {
+            ledger(store, batch_id, move |store, batch_id| {
+                store.set_row_status(batch_id, i, RowStatus::Succeeded, None)
+            })
+            .await?;
+            progress.row_done();
+            Ok(())
+        }
+        Err(e) => {
+            tracing::warn!("{}", e);
+            let (step, reason) = (e.step(), e.to_string());
+            ledger(store, batch_id, move |store, batch_id| {
+                store.set_row_outcome(batch_id, i, step, &reason)
+            })
+            .await?;
+            progress.row_failed(i, e.to_string());
+            match e.is_fatal() {
+                true => Err(e),
+                false => Ok(()),
+            }
+        }
+    }
 }
 
-pub fn save_btreemap_to_csv(
-    path: &str,
-    map: &BTreeMap<String, f64>,
-) -> Result<(), Box<dyn std::error::Error>> {
+/// Entity, then account, then payment, for one row. Entities and accounts
+/// already created for another row are [reused](shared_step).
+async fn payouts_row(
+    client: &caller::MethodClient,
+    store: &Arc<store::Store>,
+    batch_id: &str,
+    i: usize,
+    row: &xml_parser::Row,
+    progress: &jobs::Progress,
+) -> Result<()> {
+    let key = |step: &str| caller::idempotency_key(batch_id, i, step);
+
+    let individual = shared_step(
+        client,
+        store,
+        batch_id,
+        i,
+        "individual entity",
+        &row.employee.dunkin_id,
+        progress,
+        async {
+            Ok(client
+                .make_new_individual_entity(row, &key("individual entity"))
+                .await?
+                .id)
+        },
+    )
+    .await?;
+
+    let corporation = shared_step(
+        client,
+        store,
+        batch_id,
+        i,
+        "corporation entity",
+        &row.payor.dunkin_id,
+        progress,
+        async {
+            Ok(client
+                .make_new_corporation_entity(row, &key("corporation entity"))
+                .await?
+                .id)
+        },
+    )
+    .await?;
+
+    // the same bank account is a separate Method account for each payor
+    let ach = format!(
+        "{corporation}/{}/{}",
+        row.payor.abarouting, row.payor.account_number
+    );
+    let corp_account = shared_step(
+        client,
+        store,
+        batch_id,
+        i,
+        "ach account",
+        &ach,
+        progress,
+        async {
+            Ok(client
+                .make_new_account_entity(row, &corporation, &key("ach account"))
+                .await?
+                .id)
+        },
+    )
+    .await?;
+
+    let liability = format!(
+        "{individual}/{}/{}",
+        row.payee.plaid_id, row.payee.account_number
+    );
+    let loan_account = shared_step(
+        client,
+        store,
+        batch_id,
+        i,
+        "liability account",
+        &liability,
+        progress,
+        async {
+            Ok(client
+                .resolve_liability_account(row, &individual, &key("liability account"))
+                .await?
+                .id)
+        },
+    )
+    .await?;
+
+    // payment
+    resumable_step(store, batch_id, i, "payment", progress, async {
+        let resp = client
+            .make_new_payment_entity(row, &corp_account, &loan_account, &key("payment"))
+            .await?;
+        let id = resp.id.clone();
+        ledger(store, batch_id, move |store, batch_id| {
+            store.record_payment(batch_id, i, &resp)
+        })
+        .await?;
+        Ok(id)
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// Lowercase hex of `bytes`.
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub(crate) fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+pub fn save_btreemap_to_csv(path: &str, map: &BTreeMap<String, money::Money>) -> Result<()> {
     let mut wtr = WriterBuilder::new().from_path(path)?;
     for (key, value) in map {
-        wtr.write_record(&[key, &value.to_string()])?;
+        wtr.write_record([key, &value.to_string()])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+pub fn save_payments_to_csv(path: &str, payments: &[caller::Payment]) -> Result<()> {
+    let mut wtr = WriterBuilder::new().from_path(path)?;
+    wtr.write_record([
+        "id",
+        "status",
+        "amount",
+        "source",
+        "destination",
+        "estimated_completion_date",
+        "created_at",
+        "updated_at",
+        "error",
+    ])?;
+    for p in payments {
+        wtr.write_record([
+            p.id.as_str(),
+            p.status.as_str(),
+            &p.amount.to_string(),
+            &p.source,
+            &p.destination,
+            p.estimated_completion_date.as_deref().unwrap_or_default(),
+            &p.created_at,
+            &p.updated_at,
+            p.error
+                .as_ref()
+                .map(|e| e.message.as_str())
+                .unwrap_or_default(),
+        ])?;
     }
     wtr.flush()?;
     Ok(())
 }
 
-pub fn save_vec_to_csv(path: &str, vec: &Vec<Value>) -> Result<(), Box<dyn std::error::Error>> {
+pub fn save_outcomes_to_csv(path: &str, outcomes: &[store::RowOutcome]) -> Result<()> {
     let mut wtr = WriterBuilder::new().from_path(path)?;
-    for value in vec {
-        wtr.write_record(&[value.to_string()])?;
+    wtr.write_record([
+        "row",
+        "status",
+        "step",
+        "reason",
+        "employee_id",
+        "first_name",
+        "last_name",
+        "payor_id",
+        "payee_plaid_id",
+        "loan_account_number",
+        "amount",
+    ])?;
+    for o in outcomes {
+        wtr.write_record([
+            o.row.to_string().as_str(),
+            o.status.as_str(),
+            o.step.as_deref().unwrap_or_default(),
+            o.reason.as_deref().unwrap_or_default(),
+            &o.data.employee.dunkin_id,
+            &o.data.employee.first_name,
+            &o.data.employee.last_name,
+            &o.data.payor.dunkin_id,
+            &o.data.payee.plaid_id,
+            &o.data.payee.account_number,
+            &o.data.amount.to_string(),
+        ])?;
     }
     wtr.flush()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_failed_row_does_not_stop_batch() {
+        let buf = std::fs::read_to_string("data/onerow.xml").unwrap();
+        let mut rows = xml_parser::parse_xml(&buf).unwrap().row;
+        rows.push(rows[0].clone());
+        rows[0].amount = money::Money::parse("$0.00").unwrap();
+
+        let client = caller::MethodClient::new("http://127.0.0.1:1", "token").simulated();
+        let store = Arc::new(store::Store::open_in_memory().unwrap());
+        let progress = jobs::Progress::detached(rows.len());
+
+        store.create_batch("b1", &rows).unwrap();
+        let Reports(_, _, payments, outcomes) = payouts_call(&client, &store, "b1", &progress, 4)
+            .await
+            .unwrap();
+        assert_eq!(payments.len(), 1);
+        assert_eq!(outcomes.len(), 1);
+        assert_eq!(outcomes[0].row, 0);
+        assert_eq!(outcomes[0].status, RowStatus::Failed);
+        assert_eq!(outcomes[0].step.as_deref(), Some("payment"));
+
+        let status = progress.snapshot();
+        assert_eq!((status.done, status.failed, status.pending()), (1, 1, 0));
+    }
+
+    #[tokio::test]
+    async fn test_entities_and_accounts_are_shared() {
+        let buf = std::fs::read_to_string("data/onerow.xml").unwrap();
+        let mut rows = xml_parser::parse_xml(&buf).unwrap().row;
+        rows.push(rows[0].clone());
+        rows.push(rows[0].clone());
+        rows.push(rows[0].clone());
+        rows[2].employee.dunkin_id = "another employee".to_string();
+        rows[3].payor.dunkin_id = "another payor".to_string();
+
+        let client = caller::MethodClient::new("http://127.0.0.1:1", "token").simulated();
+        let store = Arc::new(store::Store::open_in_memory().unwrap());
+        let ids = |batch_id: &str, i: usize| {
+            [
+                "individual entity",
+                "corporation entity",
+                "ach account",
+                "liability account",
+            ]
+            .map(|step| store.object_id(batch_id, i, step).unwrap().unwrap())
+        };
+
+        for batch_id in ["b1", "b2"] {
+            let progress = jobs::Progress::detached(rows.len());
+            store.create_batch(batch_id, &rows).unwrap();
+            let Reports(_, _, payments, _) = payouts_call(&client, &store, batch_id, &progress, 4)
+                .await
+                .unwrap();
+            // every row is still paid
+            assert_eq!(payments.len(), 4);
+        }
+
+        let first = ids("b1", 0);
+        assert_eq!(ids("b1", 1), first);
+        assert_eq!(ids("b2", 0), first);
+        assert_eq!(ids("b2", 1), first);
+
+        // same payor and bank account, a different employee and so loan
+        let other = ids("b2", 2);
+        assert_eq!(other, ids("b1", 2));
+        assert_ne!(other[0], first[0]);
+        assert_eq!(other[1..3], first[1..3]);
+        assert_ne!(other[3], first[3]);
+
+        // same employee and bank account, paid by another payor
+        let other = ids("b1", 3);
+        assert_eq!(other, ids("b2", 3));
+        assert_eq!(other[0], first[0]);
+        assert_ne!(other[1], first[1]);
+        assert_ne!(other[2], first[2]);
+        assert_eq!(other[3], first[3]);
+
+        // never shared across Method environments
+        let live = caller::MethodClient::new("http://127.0.0.1:1", "token");
+        assert!(store
+            .shared_object(
+                live.scope(),
+                "individual entity",
+                &rows[0].employee.dunkin_id
+            )
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_rows() {
+        let buf = std::fs::read_to_string("data/onerow.xml").unwrap();
+        let row = xml_parser::parse_xml(&buf).unwrap().row.remove(0);
+        let mut rows = vec![row; 60];
+        for (i, row) in rows.iter_mut().enumerate() {
+            row.employee.dunkin_id = format!("employee {}", i % 7);
+            row.amount = money::Money::usd(100 + i as i64);
+            if i % 10 == 3 {
+                row.amount = money::Money::usd(0);
+            }
+        }
+
+        let client = caller::MethodClient::new("http://127.0.0.1:1", "token").simulated();
+        let mut runs = Vec::new();
+        for concurrency in [1, 8] {
+            let store = Arc::new(store::Store::open_in_memory().unwrap());
+            let progress = jobs::Progress::detached(rows.len());
+            store.create_batch("b1", &rows).unwrap();
+            let Reports(a, b, payments, outcomes) =
+                payouts_call(&client, &store, "b1", &progress, concurrency)
+                    .await
+                    .unwrap();
+
+            // every row of an employee got the same entity
+            let mut entities = HashMap::new();
+            for (i, row) in rows.iter().enumerate() {
+                let id = store.object_id("b1", i, "individual entity").unwrap();
+                assert_eq!(
+                    entities
+                        .entry(&row.employee.dunkin_id)
+                        .or_insert(id.clone()),
+                    &id
+                );
+            }
+            assert_eq!(entities.len(), 7);
+
+            let status = progress.snapshot();
+            assert_eq!((status.done, status.failed, status.pending()), (54, 6, 0));
+            let failed: Vec<_> = status.failures.iter().map(|(i, _)| *i).collect();
+            assert_eq!(failed, [3, 13, 23, 33, 43, 53]);
+            runs.push((a, b, payments, outcomes));
+        }
+
+        // the same reports, in the same order, however many rows ran at once.
+        // Which row got to create a shared object, so its simulated id, can
+        // differ, so the totals are compared without their accounts.
+        let (a, b, payments, outcomes) = &runs[0];
+        let amounts = |p: &[caller::Payment]| p.iter().map(|p| p.amount).collect::<Vec<_>>();
+        let totals = |m: &BTreeMap<String, money::Money>| {
+            let mut totals: Vec<_> = m.values().copied().collect();
+            totals.sort();
+            totals
+        };
+        assert_eq!(totals(&runs[1].0), totals(a));
+        assert_eq!(totals(&runs[1].1), totals(b));
+        assert_eq!(amounts(&runs[1].2), amounts(payments));
+        assert_eq!(&runs[1].3, outcomes);
+        assert_eq!(payments[0].amount, money::Money::usd(100));
+    }
+
+    #[tokio::test]
+    async fn test_ledger_failure_fails_the_row() {
+        let buf = std::fs::read_to_string("data/onerow.xml").unwrap();
+        let rows = xml_parser::parse_xml(&buf).unwrap().row;
+        let path = std::env::temp_dir().join(format!("ifdohtem-{}.db", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+
+        let client = caller::MethodClient::new("http://127.0.0.1:1", "token").simulated();
+        let store = Arc::new(store::Store::open(path).unwrap());
+        let progress = jobs::Progress::detached(rows.len());
+        store.create_batch("b1", &rows).unwrap();
+        // the ledger breaks under the row's first step
+        rusqlite::Connection::open(path)
+            .unwrap()
+            .execute("DROP TABLE shared_objects", [])
+            .unwrap();
+
+        let e = payouts_call(&client, &store, "b1", &progress, 4)
+            .await
+            .err()
+            .unwrap();
+        assert!(e.is_fatal());
+        assert_eq!(e.step(), Some("individual entity"));
+        let outcomes = store.unsuccessful_rows("b1").unwrap();
+        assert_eq!(outcomes.len(), 1);
+        assert_eq!(outcomes[0].status, RowStatus::Failed);
+        assert_eq!(outcomes[0].step.as_deref(), Some("individual entity"));
+        assert_eq!(store.batch_status("b1").unwrap(), Some(BatchStatus::Failed));
+
+        drop(store);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{path}{suffix}"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resumable_step_skips_recorded_steps() {
+        let store = Arc::new(store::Store::open_in_memory().unwrap());
+        let progress = jobs::Progress::detached(1);
+        store
+            .record_object("b1", 0, "individual entity", "ent_1")
+            .unwrap();
+
+        let id = resumable_step(&store, "b1", 0, "individual entity", &progress, async {
+            unreachable!("a recorded step must not be sent again")
+        })
+        .await
+        .unwrap();
+        assert_eq!(id, "ent_1");
+
+        let id = resumable_step(&store, "b1", 0, "corporation entity", &progress, async {
+            Ok("ent_2".to_string())
+        })
+        .await
+        .unwrap();
+        assert_eq!(id, "ent_2");
+        assert_eq!(
+            store
+                .object_id("b1", 0, "corporation entity")
+                .unwrap()
+                .as_deref(),
+            Some("ent_2")
+        );
+    }
+}
@@ -1,61 +1,288 @@
-use actix_multipart::{
-    form::{
-        bytes::Bytes,
-        tempfile::{TempFile, TempFileConfig},
-        MultipartForm,
-    },
-    Multipart,
+use actix_multipart::form::{tempfile::TempFile, text::Text, MultipartForm};
+use actix_web::{
+    body::MessageBody,
+    dev::{Payload, Service, ServiceFactory, ServiceRequest, ServiceResponse},
+    error::{ErrorBadRequest, InternalError},
+    get,
+    http::Method,
+    middleware::DefaultHeaders,
+    post, web, App, FromRequest, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder,
+    ResponseError,
 };
-use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder};
-use ifdohtem::xml_parser::*;
-use ifdohtem::*;
-use std::io::Read;
-use tracing::{debug, info};
+use futures_util::future::{ready, Either, LocalBoxFuture};
+use ifdohtem::aba::AchDirectory;
+use ifdohtem::auth::{hash_password, session_ttl_from_env, Role, User, Users};
+use ifdohtem::caller::MethodClient;
+use ifdohtem::input::{self, Format, InputConfig};
+use ifdohtem::jobs::{JobQueue, JobState, JobStatus};
+use ifdohtem::reports::{LinkError, ReportLinks, REPORT_KINDS};
+use ifdohtem::session::Session;
+use ifdohtem::store::{BatchStatus, BatchSummary, Store};
+use ifdohtem::validation::{validate, RowError};
+use ifdohtem::xml_parser::Row;
+use std::io::BufReader;
+use tracing::{info, warn};
 
 #[derive(Debug, MultipartForm)]
 struct UploadForm {
     #[multipart(rename = "file")]
     file: TempFile,
+    csrf_token: Option<Text<String>>,
 }
 
+/// Names a staged upload of the caller's session, or, for a dry run, a
+/// batch waiting for approval.
 #[derive(serde::Deserialize)]
-struct ConfirmForm {
-    tmpfile_path: String,
+struct UploadIdForm {
+    upload_id: String,
+}
+
+/// What every form posts, whatever else it does.
+#[derive(Default, serde::Deserialize)]
+struct CsrfForm {
+    #[serde(default)]
+    csrf_token: String,
+}
+
+/// A posted form, from the session it was served to. Extracting it refuses
+/// the request with [`check_csrf`]'s page unless the form carries the
+/// session's CSRF token, before anything else is looked at, so no handler
+/// taking one can forget the check. Forms with nothing but the token
+/// extract a bare `CheckedForm`.
+struct CheckedForm<T = serde::de::IgnoredAny>(T);
+
+impl<T: serde::de::DeserializeOwned + 'static> FromRequest for CheckedForm<T> {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        let body = web::Bytes::from_request(&req, payload);
+        Box::pin(async move {
+            let body = body.await?;
+            let form: CsrfForm = serde_urlencoded::from_bytes(&body).unwrap_or_default();
+            if let Some(refused) = check_csrf(&req, &form.csrf_token) {
+                return Err(InternalError::from_response("bad CSRF token", refused).into());
+            }
+            serde_urlencoded::from_bytes(&body)
+                .map(CheckedForm)
+                .map_err(ErrorBadRequest)
+        })
+    }
+}
+
+/// The hidden field carrying the CSRF token of `req`'s session, for every
+/// form that posts.
+fn csrf_field(req: &HttpRequest) -> String {
+    match Session::existing(req) {
+        Some(session) => format!(
+            r#"<input type="hidden" name="csrf_token" value="{}">"#,
+            session.csrf_token()
+        ),
+        None => String::new(),
+    }
+}
+
+/// `None` if `token` is the CSRF token of `req`'s session, the refusal
+/// otherwise. [`CheckedForm`] checks it for every urlencoded form; the
+/// upload, a multipart form, checks it itself.
+fn check_csrf(req: &HttpRequest, token: &str) -> Option<HttpResponse> {
+    if Session::existing(req).is_some_and(|session| session.verify_csrf(token)) {
+        return None;
+    }
+    warn!("refused {} {}: bad CSRF token", req.method(), req.path());
+    Some(HttpResponse::Forbidden().content_type("text/html").body(
+        r#"<!DOCTYPE html>
+        <html lang="en">
+        <head>
+            <meta charset="UTF-8">
+            <title>Request refused</title>
+        </head>
+        <body>
+            <h2>This request was refused</h2>
+            <p>The form it came from was not served to your current session: it may be
+            from before you last signed in, or from another site. Nothing was changed.</p>
+            <p>Go back, reload the page and try again, or <a href="/payouts">start over</a>.</p>
+        </body>
+        </html>"#,
+    ))
+}
+
+/// Set on every response: no scripts, styles or frames, forms only to this
+/// site, HTTPS only, and content types taken as sent.
+fn security_headers() -> DefaultHeaders {
+    DefaultHeaders::new()
+        .add((
+            "Content-Security-Policy",
+            "default-src 'none'; form-action 'self'; frame-ancestors 'none'; base-uri 'none'",
+        ))
+        .add(("X-Frame-Options", "DENY"))
+        .add((
+            "Strict-Transport-Security",
+            "max-age=63072000; includeSubDomains",
+        ))
+        .add(("X-Content-Type-Options", "nosniff"))
+        // report links carry their signature
+        .add(("Referrer-Policy", "no-referrer"))
+}
+
+/// Rows shown in the preview table; the rest are only counted.
+const PREVIEW_ROWS: usize = 100;
+
+/// Batches listed on the history page.
+const HISTORY_BATCHES: usize = 200;
+
+/// Anyone with a role may follow jobs and download their reports.
+const ANY_ROLE: &[Role] = &Role::ALL;
+
+/// `None` if `user` has one of `roles`, the refusal otherwise.
+fn require(user: &User, roles: &[Role]) -> Option<HttpResponse> {
+    if user.has_any(roles) {
+        return None;
+    }
+    let roles = roles
+        .iter()
+        .map(|r| r.as_str())
+        .collect::<Vec<_>>()
+        .join(" or ");
+    Some(HttpResponse::Forbidden().body(format!("This needs the {roles} role")))
+}
+
+/// `None` if `user` may see `batch`, its jobs and their reports: its
+/// uploader may, and so may anyone reviewing batches. The refusal otherwise.
+fn require_batch_reader(user: &User, batch: &BatchSummary) -> Option<HttpResponse> {
+    if batch.uploaded_by.as_deref() == Some(user.name.as_str()) {
+        return None;
+    }
+    require(user, &[Role::Approver, Role::Auditor, Role::Admin])
+}
+
+/// The status of job `id`, if `user` may see its batch.
+fn readable_job(
+    user: &User,
+    store: &Store,
+    jobs: &JobQueue,
+    id: &str,
+) -> Result<JobStatus, HttpResponse> {
+    let not_found = || HttpResponse::NotFound().body("Job not found");
+    let status = jobs.status(id).ok_or_else(not_found)?;
+    // a dry run's batch is in the ledger too, under the same id
+    match store.batch(&status.batch_id) {
+        Ok(Some(batch)) => match require_batch_reader(user, &batch) {
+            Some(denied) => Err(denied),
+            None => Ok(status),
+        },
+        Ok(None) => Err(not_found()),
+        Err(e) => Err(e.error_response()),
+    }
+}
+
+const PREVIEW_HEADER: &str = "<tr><td>payer id</td><td>bank</td><td>pay to amount</td><td>first name</td><td>last name</td></tr>";
+
+fn preview_row_html(row: &Row, directory: Option<&AchDirectory>) -> String {
+    let bank = directory
+        .and_then(|d| d.lookup(&row.payor.abarouting))
+        .map(|bank| bank.name.as_str())
+        .unwrap_or_default();
+    format!(
+        "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+        escape_html(&row.payor.dunkin_id),
+        escape_html(bank),
+        row.amount,
+        escape_html(&row.employee.first_name),
+        escape_html(&row.employee.last_name),
+    )
 }
 
 #[post("/payouts")]
-async fn payouts(MultipartForm(form): MultipartForm<UploadForm>) -> impl Responder {
-    let mut buf = String::new();
-    form.file.file.as_file().read_to_string(&mut buf).unwrap();
-    let a = parse_xml(&buf).unwrap();
-
-    let mut table_html = String::new();
-    table_html.push_str("<table border=\"1\">");
-    table_html.push_str(
-        "<tr><td>payer id</td><td>pay to amount</td><td>first name</td><td>last name</td></tr>",
-    );
-    for row in a.row {
-        table_html.push_str("<tr>");
+async fn payouts(
+    req: HttpRequest,
+    user: User,
+    store: web::Data<Store>,
+    directory: web::Data<Option<AchDirectory>>,
+    input: web::Data<InputConfig>,
+    MultipartForm(form): MultipartForm<UploadForm>,
+) -> impl Responder {
+    if let Some(denied) = require(&user, &[Role::Uploader]) {
+        return denied;
+    }
+    let token = form
+        .csrf_token
+        .as_ref()
+        .map(|t| t.as_str())
+        .unwrap_or_default();
+    if let Some(refused) = check_csrf(&req, token) {
+        return refused;
+    }
+    let batch_id = uuid::Uuid::new_v4().to_string();
+    let file = match form.file.file.reopen() {
+        Ok(file) => BufReader::new(file),
+        Err(e) => return ifdohtem::Error::from(e).error_response(),
+    };
+    // XML was the only format before, so it is still the fallback
+    let format = Format::detect(
+        form.file.content_type.as_ref().map(|t| t.essence_str()),
+        form.file.file_name.as_deref(),
+    )
+    .unwrap_or(Format::Xml);
+
+    // stream the rows into the ledger, keeping only the preview in memory;
+    // any invalid row rolls the whole import back. Reading a large file
+    // takes a while, so it happens off the worker thread.
+    let import = {
+        let batch_id = batch_id.clone();
+        let store = store.clone();
+        web::block(move || {
+            let mut table_html = String::new();
+            table_html.push_str("<table border=\"1\">");
+            table_html.push_str(PREVIEW_HEADER);
 
+            let directory = directory.as_ref().as_ref();
+            let mut validated =
+                validate(input::open(format, file, &input)).with_directory(directory);
+            let rows = validated.by_ref().enumerate().map(|(i, row)| {
+                if let Ok(row) = &row {
+                    if i < PREVIEW_ROWS {
+                        table_html.push_str(&preview_row_html(row, directory));
+                    }
+                }
+                row
+            });
+            let imported = store.import_batch(&batch_id, rows);
+            (imported, validated.errors().to_vec(), table_html)
+        })
+    };
+    let (imported, errors, mut table_html) = match import.await {
+        Ok(import) => import,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    if !errors.is_empty() {
+        return invalid_upload(&errors);
+    }
+    let row_count = match imported {
+        Ok(count) => count,
+        Err(e) => return e.error_response(),
+    };
+    table_html.push_str("</table>");
+    if row_count > PREVIEW_ROWS {
         table_html.push_str(&format!(
-            "<td>{}</td><td>{}</td><td>{}</td><td>{}</td>",
-            row.payor.dunkin_id.clone(),
-            row.amount.clone(),
-            row.employee.first_name.clone(),
-            row.employee.last_name.clone(),
+            "<p>... and {} more rows</p>",
+            row_count - PREVIEW_ROWS
         ));
-        table_html.push_str("</tr>");
     }
-    table_html.push_str("</table>");
 
-    let new_path = format!(
-        "{}/tmp/{}",
-        std::env::current_dir().unwrap().to_str().unwrap(),
-        uuid::Uuid::new_v4(),
+    // the rows are in the ledger now, so the file itself is not kept; the
+    // upload is only reachable by its id, from the session that made it
+    let Some(session) = Session::existing(&req) else {
+        return HttpResponse::Unauthorized().body("Sign in first");
+    };
+    if let Err(e) = store.stage_upload(&batch_id, &session.key(), &user.name) {
+        return e.error_response();
+    }
+    info!(
+        "{} staged upload {} of {} rows",
+        user.name, batch_id, row_count
     );
-    info!("saving file to {}", new_path);
-    form.file.file.persist(new_path.clone()).unwrap();
-    info!("saved file to {}", new_path);
 
     HttpResponse::Ok().content_type("text/html").body(format!(
         r#"<!DOCTYPE html>
@@ -67,109 +294,1147 @@ async fn payouts(MultipartForm(form): MultipartForm<UploadForm>) -> impl Respond
         </head>
         <body>
            <h2>Your CSV data</h2>
-            <form action="/confirm_payment" method="post">
+            <form action="/payouts/submit" method="post">
                {table_html}
-                <input type="hidden" name="tmpfile_path" value="{new_path}">
+                <input type="hidden" name="upload_id" value="{batch_id}">
+                {csrf}
                 <br>
-                <button type="submit" formaction="/payouts/confirm_payment">Confirm</button>
+                <button type="submit" formaction="/payouts/dry_run">Dry run</button>
+                <button type="submit" formaction="/payouts/submit">Submit for approval</button>
                <button type="submit" formaction="/payouts/cancel_payment">Cancel</button>
             </form>
+            <p>Another approver has to approve the batch before anything is paid.</p>
         </body>
-        </html>"#
+        </html>"#,
+        csrf = csrf_field(&req),
     ))
 }
 
+/// The preview of an upload that did not pass validation: every problem,
+/// and no way to go on with it.
+fn invalid_upload(errors: &[RowError]) -> HttpResponse {
+    let mut rows_html = String::new();
+    for e in errors {
+        rows_html.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            e.row,
+            e.position.line,
+            e.position.column,
+            escape_html(&e.field),
+            escape_html(&e.reason),
+        ));
+    }
+
+    HttpResponse::UnprocessableEntity()
+        .content_type("text/html")
+        .body(format!(
+            r#"<!DOCTYPE html>
+        <html lang="en">
+        <head>
+            <meta charset="UTF-8">
+            <title>Payouts Form</title>
+        </head>
+        <body>
+            <h2>{} problems found</h2>
+            <p>Nothing was recorded. Fix the file and upload it again.</p>
+            <table border="1">
+                <tr><td>row</td><td>line</td><td>column</td><td>field</td><td>problem</td></tr>
+                {rows_html}
+            </table>
+            <p><a href="/payouts">Upload another file</a></p>
+        </body>
+        </html>"#,
+            errors.len()
+        ))
+}
+
 #[get("/payouts")]
-async fn index() -> impl Responder {
-    let html = r#"<html>
+async fn index(req: HttpRequest, user: User) -> impl Responder {
+    let csrf = csrf_field(&req);
+    let mut sections = String::new();
+    if user.has(Role::Uploader) {
+        sections.push_str(&format!(
+            r#"<form method="post" enctype="multipart/form-data">
+                {csrf}
+                <input type="file" multiple name="file" accept=".xml,.csv,.json"/>
+                <button type="submit">Submit</button>
+            </form>"#
+        ));
+    }
+    let mut links = Vec::new();
+    if user.has(Role::Approver) {
+        links.push(r#"<a href="/payouts/approvals">Batches waiting for approval</a>"#);
+    }
+    if user.has_any(&[Role::Approver, Role::Auditor, Role::Admin]) {
+        links.push(r#"<a href="/payouts/batches">All batches</a>"#);
+    }
+    if user.has(Role::Admin) {
+        links.push(r#"<a href="/payouts/interrupted">Interrupted batches</a>"#);
+        links.push(r#"<a href="/admin/users">Users and roles</a>"#);
+    }
+    for link in links {
+        sections.push_str(&format!("<p>{link}</p>"));
+    }
+    if user.roles.is_empty() {
+        sections.push_str("<p>You have no role yet; ask an admin for one.</p>");
+    }
+
+    let html = format!(
+        r#"<html>
         <head><title>Upload Test</title></head>
         <body>
-            <form target="/" method="post" enctype="multipart/form-data">
-                <input type="file" multiple name="file"/>
-                <button type="submit">Submit</button>
+            <form action="/logout" method="post">
+                {csrf}
+                Signed in as {name} ({roles}) <button type="submit">Sign out</button>
             </form>
+            {sections}
         </body>
-    </html>"#;
-
-    HttpResponse::Ok().body(html)
-}
-
-#[post("/payouts/confirm_payment")]
-async fn confim_payment(form: web::Form<ConfirmForm>) -> impl Responder {
-    let tmpfile_path = &form.tmpfile_path;
-    let mut buf = String::new();
-    std::fs::File::open(&tmpfile_path)
-        .unwrap()
-        .read_to_string(&mut buf)
-        .unwrap();
-
-    // parse xml
-    let a = parse_xml(&buf).unwrap();
-
-    match payouts_call(a.row).await {
-        Ok(Reports(a, b, c)) => {
-            let id = uuid::Uuid::new_v4();
-            let a_path = format!(
-                "{}/tmp/{}_a.csv",
-                std::env::current_dir().unwrap().to_str().unwrap(),
-                id,
-            );
+    </html>"#,
+        name = escape_html(&user.name),
+        roles = user
+            .roles
+            .iter()
+            .map(|r| r.as_str())
+            .collect::<Vec<_>>()
+            .join(", "),
+    );
 
-            let b_path = format!(
-                "{}/tmp/{}_b.csv",
-                std::env::current_dir().unwrap().to_str().unwrap(),
-                id,
-            );
+    HttpResponse::Ok().content_type("text/html").body(html)
+}
 
-            let c_path = format!(
-                "{}/tmp/{}_c.csv",
-                std::env::current_dir().unwrap().to_str().unwrap(),
-                id,
-            );
+#[derive(serde::Deserialize)]
+struct LoginForm {
+    username: String,
+    password: String,
+}
+
+fn login_page(message: &str, session: &Session) -> String {
+    format!(
+        r#"<html>
+        <head><title>Sign in</title></head>
+        <body>
+            <h2>Sign in</h2>
+            {message}
+            <form action="/login" method="post">
+                <input type="hidden" name="csrf_token" value="{csrf_token}">
+                <p><label>User name <input name="username" autocomplete="username" required></label></p>
+                <p><label>Password <input name="password" type="password" autocomplete="current-password" required></label></p>
+                <button type="submit">Sign in</button>
+            </form>
+        </body>
+    </html>"#,
+        csrf_token = session.csrf_token(),
+    )
+}
+
+/// The sign-in form. Without a session cookie yet, it starts one the ledger
+/// does not know, only so that the form has a CSRF token.
+#[get("/login")]
+async fn login_form(req: HttpRequest) -> impl Responder {
+    let mut response = HttpResponse::Ok();
+    let session = Session::existing(&req).unwrap_or_else(|| {
+        let session = Session::new();
+        response.cookie(session.cookie());
+        session
+    });
+    response
+        .content_type("text/html")
+        .body(login_page("", &session))
+}
+
+/// Sign in under a new session, whatever cookie came with the request.
+#[post("/login")]
+async fn login(
+    req: HttpRequest,
+    store: web::Data<Store>,
+    users: web::Data<Users>,
+    CheckedForm(form): CheckedForm<LoginForm>,
+) -> impl Responder {
+    // Argon2 is slow on purpose, so keep it off the worker
+    let check = {
+        let store = store.clone();
+        let username = form.username.clone();
+        web::block(move || users.authenticate(&store, &username, &form.password))
+    };
+    let user = match check.await {
+        Ok(Ok(Some(user))) => user,
+        Ok(Ok(None)) => {
+            warn!("failed sign in as {:?}", form.username);
+            let session = Session::existing(&req).unwrap_or_default();
+            return HttpResponse::Unauthorized()
+                .content_type("text/html")
+                .body(login_page("<p>Wrong user name or password.</p>", &session));
+        }
+        Ok(Err(e)) => return e.error_response(),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    if let Some(old) = Session::existing(&req) {
+        if let Err(e) = store.delete_session(&old.key()) {
+            return e.error_response();
+        }
+    }
+    let session = Session::new();
+    if let Err(e) = store.create_session(&session.key(), &user.name, session_ttl_from_env()) {
+        return e.error_response();
+    }
+    info!("{} signed in", user.name);
+
+    HttpResponse::SeeOther()
+        .cookie(session.cookie())
+        .insert_header(("Location", "/payouts"))
+        .finish()
+}
+
+#[post("/logout")]
+async fn logout(
+    req: HttpRequest,
+    user: User,
+    store: web::Data<Store>,
+    _form: CheckedForm,
+) -> impl Responder {
+    if let Some(session) = Session::existing(&req) {
+        if let Err(e) = store.delete_session(&session.key()) {
+            return e.error_response();
+        }
+    }
+    info!("{} signed out", user.name);
+
+    HttpResponse::SeeOther()
+        .cookie(Session::removal_cookie())
+        .insert_header(("Location", "/login"))
+        .finish()
+}
+
+/// For load balancers and monitoring; the only route open without signing
+/// in, besides signing in itself.
+#[get("/health")]
+async fn health() -> impl Responder {
+    HttpResponse::Ok().body("ok")
+}
+
+/// Routes served without signing in.
+const PUBLIC_PATHS: &[&str] = &["/health", "/login"];
+
+/// The user signed in under `req`'s session cookie, with their current
+/// roles.
+fn signed_in_user(req: &HttpRequest) -> Option<User> {
+    let store = req.app_data::<web::Data<Store>>()?;
+    let users = req.app_data::<web::Data<Users>>()?;
+    let session = Session::existing(req)?;
+    let user = store
+        .session_user(&session.key())
+        .and_then(|name| name.map(|name| users.user(store, &name)).transpose());
+    match user {
+        Ok(user) => user,
+        Err(e) => {
+            warn!("could not look the session up: {e}");
+            None
+        }
+    }
+}
+
+/// Hand a staged upload over to the approvers. Nothing runs until one of
+/// them, other than the uploader, approves it.
+#[post("/payouts/submit")]
+async fn submit_for_approval(
+    req: HttpRequest,
+    user: User,
+    store: web::Data<Store>,
+    CheckedForm(form): CheckedForm<UploadIdForm>,
+) -> impl Responder {
+    if let Some(denied) = require(&user, &[Role::Uploader]) {
+        return denied;
+    }
+    // the upload id is the batch id, and an upload is submitted only once
+    let batch_id = form.upload_id;
+    let Some(session) = Session::existing(&req) else {
+        return no_such_upload();
+    };
+    match store.submit_upload(&batch_id, &session.key(), &user.name) {
+        Ok(true) => {}
+        Ok(false) => return no_such_upload(),
+        Err(e) => return e.error_response(),
+    }
+    info!("{} submitted batch {} for approval", user.name, batch_id);
+
+    HttpResponse::SeeOther()
+        .insert_header(("Location", format!("/payouts/batches/{batch_id}")))
+        .finish()
+}
+
+/// Unknown, someone else's, or already submitted or cancelled.
+fn no_such_upload() -> HttpResponse {
+    HttpResponse::NotFound().body("No such upload waiting to be submitted")
+}
+
+/// Run a batch through the whole pipeline against the simulated provider
+/// and a throwaway ledger: the uploader's staged upload, before submitting
+/// it, or a batch an approver is reviewing. Its status does not change.
+#[post("/payouts/dry_run")]
+async fn dry_run(
+    req: HttpRequest,
+    user: User,
+    client: web::Data<MethodClient>,
+    store: web::Data<Store>,
+    jobs: web::Data<JobQueue>,
+    reports: web::Data<ReportLinks>,
+    CheckedForm(form): CheckedForm<UploadIdForm>,
+) -> impl Responder {
+    if let Some(denied) = require(&user, &[Role::Uploader, Role::Approver]) {
+        return denied;
+    }
+    let batch_id = form.upload_id;
+    // the uploader's own staged upload, or a batch an approver reviews
+    let allowed = match Session::existing(&req) {
+        Some(session) if user.has(Role::Uploader) => store.is_staged(&batch_id, &session.key()),
+        _ => Ok(false),
+    }
+    .and_then(|staged| {
+        Ok(staged
+            || (user.has(Role::Approver)
+                && store.batch_status(&batch_id)? == Some(BatchStatus::PendingApproval)))
+    });
+    match allowed {
+        Ok(true) => {}
+        Ok(false) => return no_such_upload(),
+        Err(e) => return e.error_response(),
+    }
+
+    // a copy of the rows already validated into the ledger, on disk and
+    // off the worker thread however many rows there are
+    let copy = {
+        let batch_id = batch_id.clone();
+        let store = store.clone();
+        web::block(move || {
+            let row_count = store.row_count(&batch_id)?;
+            let rows = (0..row_count).filter_map(|i| store.batch_row(&batch_id, i).transpose());
+            let throwaway = Store::open_temporary()?;
+            throwaway.import_batch(&batch_id, rows)?;
+            Ok::<_, ifdohtem::Error>(throwaway)
+        })
+    };
+    let throwaway = match copy.await {
+        Ok(Ok(throwaway)) => std::sync::Arc::new(throwaway),
+        Ok(Err(e)) => return e.error_response(),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    let report_dir = reports.dir().display().to_string();
+    let id = match jobs.spawn(client.simulated(), throwaway, batch_id, report_dir) {
+        Ok(id) => id,
+        Err(e) => return e.error_response(),
+    };
+    info!("{} queued dry run job {}", user.name, id);
+
+    HttpResponse::SeeOther()
+        .insert_header(("Location", format!("/payouts/jobs/{id}")))
+        .finish()
+}
+
+#[get("/payouts/jobs/{id}")]
+async fn job_status(
+    user: User,
+    store: web::Data<Store>,
+    jobs: web::Data<JobQueue>,
+    reports: web::Data<ReportLinks>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if let Some(denied) = require(&user, ANY_ROLE) {
+        return denied;
+    }
+    let id = path.into_inner();
+    let status = match readable_job(&user, &store, &jobs, &id) {
+        Ok(status) => status,
+        Err(refused) => return refused,
+    };
 
-            save_btreemap_to_csv(&a_path, &a).unwrap();
-            save_btreemap_to_csv(&b_path, &b).unwrap();
-            save_vec_to_csv(&c_path, &c).unwrap();
+    let (refresh, detail) = match &status.state {
+        JobState::Queued | JobState::Running => (
+            r#"<meta http-equiv="refresh" content="2">"#,
+            match status.current {
+                Some((row, step)) => format!("<p>Latest step: row {row}, {step}</p>"),
+                None => String::new(),
+            },
+        ),
+        JobState::Completed => ("", String::new()),
+        JobState::Failed(reason) => ("", format!("<p>Failed: {}</p>", escape_html(reason))),
+    };
 
-            HttpResponse::Ok().content_type("text/html").body(format!(
-                r#"
+    // a failed job still has reports for the rows it got through
+    let downloads = match &status.report_id {
+        Some(report_id) => REPORT_KINDS
+            .iter()
+            .map(|(kind, label)| {
+                format!(
+                    r#"<p><a href="{}">Download {label}</a></p>"#,
+                    escape_html(&reports.url(report_id, kind))
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        None => String::new(),
+    };
+
+    let banner = match status.simulated {
+        true => "<p><strong>Dry run: simulated, nothing was sent to Method.</strong></p>",
+        false => "",
+    };
+
+    let mut failures_html = String::new();
+    if !status.failures.is_empty() {
+        failures_html.push_str(match status.simulated {
+            true => "<h3>Rows that would fail</h3><ul>",
+            false => "<h3>Failed rows</h3><ul>",
+        });
+        for (row, reason) in &status.failures {
+            failures_html.push_str(&format!("<li>row {row}: {}</li>", escape_html(reason)));
+        }
+        failures_html.push_str("</ul>");
+    }
+
+    HttpResponse::Ok().content_type("text/html").body(format!(
+        r#"
         <html>
+            <head>{refresh}<title>Payout job {id}</title></head>
             <body>
-                <button onclick="window.location.href='/download/{id}_a.csv';">Download Report1</button>
-                <button onclick="window.location.href='/download/{id}_b.csv';">Download Report2</button>
-                <button onclick="window.location.href='/download/{id}_c.csv';">Download Report3</button>
+                <h2>Payout job {id}</h2>
+                {banner}
+                <p>Batch: <a href="/payouts/batches/{batch_id}">{batch_id}</a></p>
+                <p>State: {state}</p>
+                <p>Rows done: {done}, failed: {failed}, skipped: {skipped}, pending: {pending} (of {total})</p>
+                {detail}
+                {downloads}
+                {failures_html}
             </body>
         </html>
         "#,
-            ))
-        }
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        state = status.state.as_str(),
+        batch_id = status.batch_id,
+        done = status.done,
+        failed = status.failed,
+        skipped = status.skipped,
+        pending = status.pending(),
+        total = status.total,
+    ))
+}
+
+#[get("/payouts/jobs/{id}/status")]
+async fn job_status_json(
+    user: User,
+    store: web::Data<Store>,
+    jobs: web::Data<JobQueue>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if let Some(denied) = require(&user, ANY_ROLE) {
+        return denied;
+    }
+    match readable_job(&user, &store, &jobs, &path.into_inner()) {
+        Ok(status) => HttpResponse::Ok().json(status),
+        Err(refused) => refused,
     }
 }
 
 #[post("/payouts/cancel_payment")]
-async fn cancel_payment(form: web::Form<ConfirmForm>) -> impl Responder {
-    let tmpfile_path = &form.tmpfile_path;
-    std::fs::remove_file(&tmpfile_path).unwrap();
-    info!("deleted file to {}", tmpfile_path);
+async fn cancel_payment(
+    req: HttpRequest,
+    user: User,
+    store: web::Data<Store>,
+    CheckedForm(form): CheckedForm<UploadIdForm>,
+) -> impl Responder {
+    if let Some(denied) = require(&user, &[Role::Uploader]) {
+        return denied;
+    }
+    let batch_id = form.upload_id;
+    let Some(session) = Session::existing(&req) else {
+        return no_such_upload();
+    };
+    match store.cancel_upload(&batch_id, &session.key(), &user.name) {
+        Ok(true) => {}
+        Ok(false) => return no_such_upload(),
+        Err(e) => return e.error_response(),
+    }
+    info!("{} cancelled upload {}", user.name, batch_id);
     HttpResponse::Ok().body("Payment cancelled")
 }
 
-#[get("/download/{filename}")]
-async fn download(path: web::Path<String>) -> impl Responder {
-    let filename = path.into_inner();
-    let file_path = format!("/tmp/{}", filename);
+fn batches_table(batches: &[BatchSummary]) -> String {
+    let mut html = String::from(
+        "<table border=\"1\"><tr><td>batch</td><td>status</td><td>rows</td><td>uploaded by</td><td>reviewed by</td><td>uploaded at</td></tr>",
+    );
+    for batch in batches {
+        html.push_str(&format!(
+            r#"<tr><td><a href="/payouts/batches/{id}">{id}</a></td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>"#,
+            batch.status.as_str(),
+            batch.row_count,
+            escape_html(batch.uploaded_by.as_deref().unwrap_or_default()),
+            escape_html(batch.reviewed_by.as_deref().unwrap_or_default()),
+            batch.created_at,
+            id = escape_html(&batch.id),
+        ));
+    }
+    html.push_str("</table>");
+    html
+}
+
+#[get("/payouts/approvals")]
+async fn approvals(user: User, store: web::Data<Store>) -> impl Responder {
+    if let Some(denied) = require(&user, &[Role::Approver]) {
+        return denied;
+    }
+    let batches = match store.batches(Some(BatchStatus::PendingApproval), HISTORY_BATCHES) {
+        Ok(batches) => batches,
+        Err(e) => return e.error_response(),
+    };
+    let list_html = match batches.is_empty() {
+        true => "<p>None</p>".to_string(),
+        false => batches_table(&batches),
+    };
+
+    HttpResponse::Ok().content_type("text/html").body(format!(
+        r#"
+        <html>
+            <head><title>Batches waiting for approval</title></head>
+            <body>
+                <h2>Batches waiting for approval</h2>
+                <p>Open a batch to review, dry run, approve or reject it. Batches you uploaded need another approver.</p>
+                {list_html}
+            </body>
+        </html>
+        "#
+    ))
+}
+
+#[get("/payouts/batches")]
+async fn batch_history(user: User, store: web::Data<Store>) -> impl Responder {
+    if let Some(denied) = require(&user, &[Role::Approver, Role::Auditor, Role::Admin]) {
+        return denied;
+    }
+    let batches = match store.batches(None, HISTORY_BATCHES) {
+        Ok(batches) => batches,
+        Err(e) => return e.error_response(),
+    };
+
+    HttpResponse::Ok().content_type("text/html").body(format!(
+        r#"
+        <html>
+            <head><title>Batches</title></head>
+            <body>
+                <h2>The latest {HISTORY_BATCHES} batches</h2>
+                {table}
+            </body>
+        </html>
+        "#,
+        table = batches_table(&batches)
+    ))
+}
+
+/// A batch, its rows, who moved it through which status, and what can be
+/// done with it next. Approvers, auditors and admins see every batch;
+/// uploaders only their own.
+#[get("/payouts/batches/{id}")]
+async fn batch_page(
+    req: HttpRequest,
+    user: User,
+    store: web::Data<Store>,
+    jobs: web::Data<JobQueue>,
+    directory: web::Data<Option<AchDirectory>>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let batch_id = path.into_inner();
+    let batch = match store.batch(&batch_id) {
+        Ok(Some(batch)) => batch,
+        Ok(None) => return HttpResponse::NotFound().body("No such batch"),
+        Err(e) => return e.error_response(),
+    };
+    if let Some(denied) = require_batch_reader(&user, &batch) {
+        return denied;
+    }
+    let own = batch.uploaded_by.as_deref() == Some(user.name.as_str());
+
+    let directory = directory.as_ref().as_ref();
+    let mut table_html = format!("<table border=\"1\">{PREVIEW_HEADER}");
+    for i in 0..batch.row_count.min(PREVIEW_ROWS) {
+        match store.batch_row(&batch_id, i) {
+            Ok(Some(row)) => table_html.push_str(&preview_row_html(&row, directory)),
+            Ok(None) => {}
+            Err(e) => return e.error_response(),
+        }
+    }
+    table_html.push_str("</table>");
+    if batch.row_count > PREVIEW_ROWS {
+        table_html.push_str(&format!(
+            "<p>... and {} more rows</p>",
+            batch.row_count - PREVIEW_ROWS
+        ));
+    }
+
+    let mut history_html = String::from("<ul>");
+    let transitions = match store.transitions(&batch_id) {
+        Ok(transitions) => transitions,
+        Err(e) => return e.error_response(),
+    };
+    for t in transitions {
+        history_html.push_str(&format!(
+            "<li>{}: {} to {}{}</li>",
+            t.at,
+            t.from.map(|s| s.as_str()).unwrap_or("new"),
+            t.to.as_str(),
+            match &t.user {
+                Some(user) => format!(" by {}", escape_html(user)),
+                None => String::new(),
+            }
+        ));
+    }
+    history_html.push_str("</ul>");
+
+    let mut jobs_html = String::new();
+    for job in jobs.for_batch(&batch_id) {
+        jobs_html.push_str(&format!(
+            r#"<li><a href="/payouts/jobs/{id}">{kind} {id}</a>: {state}</li>"#,
+            id = job.id,
+            kind = if job.simulated { "Dry run" } else { "Job" },
+            state = job.state.as_str(),
+        ));
+    }
+    if !jobs_html.is_empty() {
+        jobs_html = format!("<h3>Jobs</h3><ul>{jobs_html}</ul>");
+    }
+
+    let actions = match batch.status {
+        BatchStatus::PendingApproval if own => {
+            "<p>Waiting for another approver: you uploaded this batch.</p>".to_string()
+        }
+        BatchStatus::PendingApproval if user.has(Role::Approver) => format!(
+            r#"<form method="post">
+                <input type="hidden" name="upload_id" value="{id}">
+                {csrf}
+                <button type="submit" formaction="/payouts/dry_run">Dry run</button>
+                <button type="submit" formaction="/payouts/batches/{id}/approve">Approve and run</button>
+                <button type="submit" formaction="/payouts/batches/{id}/reject">Reject</button>
+            </form>"#,
+            id = escape_html(&batch_id),
+            csrf = csrf_field(&req),
+        ),
+        _ => String::new(),
+    };
+
+    HttpResponse::Ok().content_type("text/html").body(format!(
+        r#"
+        <html>
+            <head><title>Batch {id}</title></head>
+            <body>
+                <h2>Batch {id}</h2>
+                <p>Status: {status}</p>
+                <p>Uploaded by {uploaded_by} at {created_at}; {row_count} rows</p>
+                {actions}
+                {table_html}
+                <h3>History</h3>
+                {history_html}
+                {jobs_html}
+            </body>
+        </html>
+        "#,
+        id = escape_html(&batch_id),
+        status = batch.status.as_str(),
+        uploaded_by = escape_html(batch.uploaded_by.as_deref().unwrap_or("nobody known")),
+        created_at = batch.created_at,
+        row_count = batch.row_count,
+    ))
+}
+
+/// Approve a batch waiting for approval, and run it. The uploader of a
+/// batch can never approve it, whatever their roles.
+#[post("/payouts/batches/{id}/approve")]
+async fn approve_batch(
+    user: User,
+    client: web::Data<MethodClient>,
+    store: web::Data<Store>,
+    jobs: web::Data<JobQueue>,
+    reports: web::Data<ReportLinks>,
+    path: web::Path<String>,
+    _form: CheckedForm,
+) -> impl Responder {
+    if let Some(denied) = require(&user, &[Role::Approver]) {
+        return denied;
+    }
+    let batch_id = path.into_inner();
+    match store.approve_batch(&batch_id, &user.name) {
+        Ok(true) => {}
+        Ok(false) => {
+            return match store.batch(&batch_id) {
+                Ok(Some(batch)) if batch.uploaded_by.as_deref() == Some(user.name.as_str()) => {
+                    warn!(
+                        "{} tried to approve their own batch {}",
+                        user.name, batch_id
+                    );
+                    HttpResponse::Forbidden()
+                        .body("You uploaded this batch, another approver has to approve it")
+                }
+                Ok(Some(_)) => HttpResponse::Conflict().body("Batch is not waiting for approval"),
+                Ok(None) => HttpResponse::NotFound().body("No such batch"),
+                Err(e) => e.error_response(),
+            };
+        }
+        Err(e) => return e.error_response(),
+    }
+
+    let report_dir = reports.dir().display().to_string();
+    let id = match jobs.spawn(
+        client.get_ref().clone(),
+        store.clone().into_inner(),
+        batch_id.clone(),
+        report_dir,
+    ) {
+        Ok(id) => id,
+        Err(e) => {
+            // approved, but nothing will ever run it
+            job_not_started(
+                &store,
+                &batch_id,
+                BatchStatus::Approved,
+                BatchStatus::Failed,
+                &user,
+            );
+            return e.error_response();
+        }
+    };
+    info!(
+        "{} approved batch {}, queued job {}",
+        user.name, batch_id, id
+    );
+
+    HttpResponse::SeeOther()
+        .insert_header(("Location", format!("/payouts/jobs/{id}")))
+        .finish()
+}
+
+/// Record that the job for `batch_id`, just moved to `from` by `user`,
+/// could not be started.
+fn job_not_started(store: &Store, batch_id: &str, from: BatchStatus, to: BatchStatus, user: &User) {
+    warn!(
+        "could not start a job for batch {batch_id}, moving it to {}",
+        to.as_str()
+    );
+    if let Err(e) = store.job_not_started(batch_id, from, to, &user.name) {
+        warn!("could not move batch {batch_id} to {}: {e}", to.as_str());
+    }
+}
+
+#[post("/payouts/batches/{id}/reject")]
+async fn reject_batch(
+    user: User,
+    store: web::Data<Store>,
+    path: web::Path<String>,
+    _form: CheckedForm,
+) -> impl Responder {
+    if let Some(denied) = require(&user, &[Role::Approver]) {
+        return denied;
+    }
+    let batch_id = path.into_inner();
+    match store.reject_batch(&batch_id, &user.name) {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::Conflict().body("Batch is not waiting for approval"),
+        Err(e) => return e.error_response(),
+    }
+    info!("{} rejected batch {}", user.name, batch_id);
+
+    HttpResponse::SeeOther()
+        .insert_header(("Location", "/payouts/approvals"))
+        .finish()
+}
+
+#[get("/payouts/interrupted")]
+async fn interrupted(req: HttpRequest, user: User, store: web::Data<Store>) -> impl Responder {
+    if let Some(denied) = require(&user, &[Role::Admin]) {
+        return denied;
+    }
+    let ids = match store.batches_with_status(BatchStatus::Interrupted) {
+        Ok(ids) => ids,
+        Err(e) => return e.error_response(),
+    };
+
+    let csrf = csrf_field(&req);
+    let mut list_html = String::new();
+    for id in &ids {
+        list_html.push_str(&format!(
+            r#"<li>{id}
+                <form method="post">
+                    {csrf}
+                    <button type="submit" formaction="/payouts/batches/{id}/resume">Resume</button>
+                    <button type="submit" formaction="/payouts/batches/{id}/abandon">Abandon</button>
+                </form>
+            </li>"#
+        ));
+    }
+    if ids.is_empty() {
+        list_html.push_str("<li>None</li>");
+    }
+
+    HttpResponse::Ok().content_type("text/html").body(format!(
+        r#"
+        <html>
+            <head><title>Interrupted batches</title></head>
+            <body>
+                <h2>Batches interrupted by a restart</h2>
+                <p>Resuming only runs the steps that did not finish.</p>
+                <ul>{list_html}</ul>
+            </body>
+        </html>
+        "#
+    ))
+}
+
+#[post("/payouts/batches/{id}/resume")]
+async fn resume_batch(
+    user: User,
+    client: web::Data<MethodClient>,
+    store: web::Data<Store>,
+    jobs: web::Data<JobQueue>,
+    reports: web::Data<ReportLinks>,
+    path: web::Path<String>,
+    _form: CheckedForm,
+) -> impl Responder {
+    if let Some(denied) = require(&user, &[Role::Admin]) {
+        return denied;
+    }
+    // claimed before the job starts, so resuming twice starts one job
+    let batch_id = path.into_inner();
+    match store.resume_batch(&batch_id, &user.name) {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::Conflict().body("Batch is not interrupted"),
+        Err(e) => return e.error_response(),
+    }
+
+    let report_dir = reports.dir().display().to_string();
+    let id = match jobs.spawn(
+        client.get_ref().clone(),
+        store.clone().into_inner(),
+        batch_id.clone(),
+        report_dir,
+    ) {
+        Ok(id) => id,
+        Err(e) => {
+            // free to be resumed again
+            job_not_started(
+                &store,
+                &batch_id,
+                BatchStatus::Running,
+                BatchStatus::Interrupted,
+                &user,
+            );
+            return e.error_response();
+        }
+    };
+    info!("{} resumed batch {} as job {}", user.name, batch_id, id);
+
+    HttpResponse::SeeOther()
+        .insert_header(("Location", format!("/payouts/jobs/{id}")))
+        .finish()
+}
+
+#[post("/payouts/batches/{id}/abandon")]
+async fn abandon_batch(
+    user: User,
+    store: web::Data<Store>,
+    path: web::Path<String>,
+    _form: CheckedForm,
+) -> impl Responder {
+    if let Some(denied) = require(&user, &[Role::Admin]) {
+        return denied;
+    }
+    let batch_id = path.into_inner();
+    match store.abandon_batch(&batch_id, &user.name) {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::Conflict().body("Batch is not interrupted"),
+        Err(e) => return e.error_response(),
+    }
+    info!("{} abandoned batch {}", user.name, batch_id);
+
+    HttpResponse::SeeOther()
+        .insert_header(("Location", "/payouts/interrupted"))
+        .finish()
+}
+
+/// Checked boxes of the roles form.
+#[derive(serde::Deserialize)]
+struct RolesForm {
+    uploader: Option<String>,
+    approver: Option<String>,
+    auditor: Option<String>,
+    admin: Option<String>,
+}
+
+impl RolesForm {
+    fn roles(&self) -> Vec<&'static str> {
+        [
+            (Role::Uploader, &self.uploader),
+            (Role::Approver, &self.approver),
+            (Role::Auditor, &self.auditor),
+            (Role::Admin, &self.admin),
+        ]
+        .into_iter()
+        .filter(|(_, checked)| checked.is_some())
+        .map(|(role, _)| role.as_str())
+        .collect()
+    }
+}
+
+/// The ledger's users and their roles. Users of the users file are managed
+/// in that file.
+#[get("/admin/users")]
+async fn admin_users(
+    req: HttpRequest,
+    user: User,
+    store: web::Data<Store>,
+    users: web::Data<Users>,
+) -> impl Responder {
+    if let Some(denied) = require(&user, &[Role::Admin]) {
+        return denied;
+    }
+    let names = match store.users() {
+        Ok(names) => names,
+        Err(e) => return e.error_response(),
+    };
+
+    let csrf = csrf_field(&req);
+    let mut rows_html = String::new();
+    for name in names.iter().filter(|name| !users.in_file(name)) {
+        let roles = match store.roles(name) {
+            Ok(roles) => roles,
+            Err(e) => return e.error_response(),
+        };
+        let boxes = Role::ALL
+            .iter()
+            .map(|role| {
+                format!(
+                    r#"<label><input type="checkbox" name="{role}"{checked}> {role}</label>"#,
+                    role = role.as_str(),
+                    checked = if roles.iter().any(|r| r == role.as_str()) {
+                        " checked"
+                    } else {
+                        ""
+                    },
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        rows_html.push_str(&format!(
+            r#"<li><form action="/admin/users/{name}/roles" method="post">{csrf}{name}: {boxes} <button type="submit">Save</button></form></li>"#,
+            name = escape_html(name),
+        ));
+    }
+    if rows_html.is_empty() {
+        rows_html.push_str("<li>None</li>");
+    }
+
+    HttpResponse::Ok().content_type("text/html").body(format!(
+        r#"
+        <html>
+            <head><title>Users and roles</title></head>
+            <body>
+                <h2>Users and roles</h2>
+                <p>Users of the IFDOHTEM_USERS file get their roles from it.</p>
+                <ul>{rows_html}</ul>
+            </body>
+        </html>
+        "#
+    ))
+}
+
+#[post("/admin/users/{name}/roles")]
+async fn set_user_roles(
+    user: User,
+    store: web::Data<Store>,
+    users: web::Data<Users>,
+    path: web::Path<String>,
+    CheckedForm(form): CheckedForm<RolesForm>,
+) -> impl Responder {
+    if let Some(denied) = require(&user, &[Role::Admin]) {
+        return denied;
+    }
+    let name = path.into_inner();
+    match store.password_hash(&name) {
+        Ok(Some(_)) if !users.in_file(&name) => {}
+        Ok(_) => return HttpResponse::NotFound().body("No such user in the ledger"),
+        Err(e) => return e.error_response(),
+    }
+    let roles = form.roles();
+    if let Err(e) = store.set_roles(&name, &roles) {
+        return e.error_response();
+    }
+    info!("{} set the roles of {} to {:?}", user.name, name, roles);
+
+    HttpResponse::SeeOther()
+        .insert_header(("Location", "/admin/users"))
+        .finish()
+}
+
+#[derive(serde::Deserialize)]
+struct DownloadLink {
+    expires: u64,
+    sig: String,
+}
+
+/// Serve a report through a link from [`ReportLinks::url`]. Every attempt
+/// is recorded, refused ones included.
+#[get("/reports/{report_id}/{kind}")]
+async fn download(
+    req: HttpRequest,
+    user: User,
+    store: web::Data<Store>,
+    reports: web::Data<ReportLinks>,
+    path: web::Path<(String, String)>,
+    link: web::Query<DownloadLink>,
+) -> impl Responder {
+    if let Some(denied) = require(&user, ANY_ROLE) {
+        return denied;
+    }
+    let (report_id, kind) = path.into_inner();
+    let peer = req.peer_addr().map(|a| a.ip().to_string());
+    let audit = |outcome: &str| {
+        info!(
+            "download of report {report_id}_{kind} by {} from {peer:?}: {outcome}",
+            user.name
+        );
+        store.record_download(
+            &report_id,
+            &kind,
+            peer.as_deref(),
+            Some(&user.name),
+            outcome,
+        )
+    };
+
+    let file_path = match reports.verify(&report_id, &kind, link.expires, &link.sig) {
+        Ok(file_path) => file_path,
+        Err(e) => {
+            if let Err(e) = audit(e.as_str()) {
+                return e.error_response();
+            }
+            return match e {
+                LinkError::Malformed => HttpResponse::NotFound().body(e.to_string()),
+                LinkError::BadSignature | LinkError::Expired => {
+                    HttpResponse::Forbidden().body(e.to_string())
+                }
+            };
+        }
+    };
 
     match std::fs::read(&file_path) {
-        Ok(data) => HttpResponse::Ok()
-            .content_type("text/csv")
-            .insert_header((
-                "Content-Disposition",
-                format!("attachment; filename=\"{}\"", filename),
-            ))
-            .body(data),
-        Err(_) => HttpResponse::NotFound().body("File not found"),
+        Ok(data) => {
+            if let Err(e) = audit("served") {
+                return e.error_response();
+            }
+            HttpResponse::Ok()
+                .content_type("text/csv")
+                .insert_header((
+                    "Content-Disposition",
+                    format!("attachment; filename=\"{report_id}_{kind}.csv\""),
+                ))
+                .body(data)
+        }
+        Err(_) => {
+            if let Err(e) = audit("not found") {
+                return e.error_response();
+            }
+            HttpResponse::NotFound().body("no such report")
+        }
+    }
+}
+
+#[get("/metrics/rate_limit")]
+async fn rate_limit_state(user: User, client: web::Data<MethodClient>) -> impl Responder {
+    if let Some(denied) = require(&user, ANY_ROLE) {
+        return denied;
+    }
+    HttpResponse::Ok().json(client.rate_limiter().state())
+}
+
+/// A password from the first line of stdin.
+fn read_password() -> std::io::Result<String> {
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    let password = line.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "no password on stdin",
+        ));
     }
+    Ok(password.to_string())
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Every route, behind the sign-in check, short of the app data they
+/// need: a `MethodClient`, `Store`, `JobQueue`, `Option<AchDirectory>`,
+/// `InputConfig`, `ReportLinks` and `Users`.
+fn app() -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    App::new()
+        .wrap_fn(|req, srv| {
+            let path = req.path();
+            if PUBLIC_PATHS.contains(&path) {
+                return Either::Left(srv.call(req));
+            }
+            match signed_in_user(req.request()) {
+                Some(user) => {
+                    req.extensions_mut().insert(user);
+                    Either::Left(srv.call(req))
+                }
+                None => {
+                    // pages send the browser to sign in, anything else
+                    // just fails
+                    let response = if req.method() == Method::GET {
+                        HttpResponse::SeeOther()
+                            .insert_header(("Location", "/login"))
+                            .finish()
+                    } else {
+                        HttpResponse::Unauthorized().body("not signed in")
+                    };
+                    Either::Right(ready(Ok(req.into_response(response))))
+                }
+            }
+        })
+        // outermost, so the sign-in check's answers get them too
+        .wrap(security_headers())
+        .service(health)
+        .service(login_form)
+        .service(login)
+        .service(logout)
+        .service(payouts)
+        .service(index)
+        .service(submit_for_approval)
+        .service(dry_run)
+        .service(job_status)
+        .service(job_status_json)
+        .service(cancel_payment)
+        .service(approvals)
+        .service(batch_history)
+        .service(batch_page)
+        .service(approve_batch)
+        .service(reject_batch)
+        .service(interrupted)
+        .service(resume_batch)
+        .service(abandon_batch)
+        .service(admin_users)
+        .service(set_user_roles)
+        .service(download)
+        .service(rate_limit_state)
 }
 
 #[actix_web::main]
@@ -182,15 +1447,384 @@ async fn main() -> std::io::Result<()> {
     )
     .unwrap();
 
+    // `ifdohtem hash-password` and `ifdohtem add-user NAME [ROLE]...` read a
+    // password from stdin, for the users file and the ledger's users respectively
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("hash-password") => {
+            println!("{}", hash_password(&read_password()?));
+            return Ok(());
+        }
+        Some("add-user") => {
+            let usage =
+                "usage: ifdohtem add-user NAME [uploader|approver|auditor|admin]... < password";
+            let Some(name) = args.get(1).filter(|n| !n.trim().is_empty()) else {
+                eprintln!("{usage}");
+                std::process::exit(2);
+            };
+            let Some(roles) = args[2..]
+                .iter()
+                .map(|r| Role::parse(r).map(|r| r.as_str()))
+                .collect::<Option<Vec<_>>>()
+            else {
+                eprintln!("{usage}");
+                std::process::exit(2);
+            };
+            let store = Store::open_from_env().map_err(std::io::Error::other)?;
+            store
+                .add_user(name, &hash_password(&read_password()?))
+                .map_err(std::io::Error::other)?;
+            // without roles, an existing user keeps theirs
+            if !roles.is_empty() {
+                store
+                    .set_roles(name, &roles)
+                    .map_err(std::io::Error::other)?;
+            }
+            println!("added user {name}");
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let client = web::Data::new(MethodClient::from_env()?);
+    info!("calling Method at {}", client.base_url());
+    let store = web::Data::new(Store::open_from_env().map_err(std::io::Error::other)?);
+    let jobs = web::Data::new(JobQueue::new().with_concurrency(ifdohtem::concurrency_from_env()));
+    let directory = web::Data::new(AchDirectory::from_env()?);
+    let input = web::Data::new(InputConfig::from_env()?);
+    let reports = web::Data::new(ReportLinks::from_env()?);
+    let users = web::Data::new(Users::from_env()?);
+    info!("{} users from IFDOHTEM_USERS", users.file_len());
+    info!("writing reports to {}", reports.dir().display());
+    match directory.as_ref() {
+        Some(d) => info!("loaded {} banks from the Fed ACH directory", d.len()),
+        None => warn!("FED_ACH_DIRECTORY is not set, routing numbers are only checksummed"),
+    }
+
+    for id in store
+        .discard_incomplete_imports()
+        .map_err(std::io::Error::other)?
+    {
+        warn!(
+            "discarded batch {}, its upload was cut short by a restart",
+            id
+        );
+    }
+
+    // nothing is running yet, so whatever the ledger says is running died
+    // with the previous process
+    for id in store.mark_interrupted().map_err(std::io::Error::other)? {
+        warn!(
+            "batch {} was interrupted, resume or abandon it at /payouts/interrupted",
+            id
+        );
+    }
+
     HttpServer::new(move || {
-        App::new()
-            .service(payouts)
-            .service(index)
-            .service(confim_payment)
-            .service(cancel_payment)
-            .service(download)
+        app()
+            .app_data(client.clone())
+            .app_data(store.clone())
+            .app_data(jobs.clone())
+            .app_data(directory.clone())
+            .app_data(input.clone())
+            .app_data(reports.clone())
+            .app_data(users.clone())
     })
     .bind(("127.0.0.1", 8080))?
     .run()
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test};
+
+    /// [`app`] over `store`, calling a Method nobody listens on.
+    fn test_app(
+        store: &web::Data<Store>,
+    ) -> App<
+        impl ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse<impl MessageBody>,
+            Error = actix_web::Error,
+            InitError = (),
+        >,
+    > {
+        app()
+            .app_data(web::Data::new(MethodClient::new("http://127.0.0.1:9", "x")))
+            .app_data(store.clone())
+            .app_data(web::Data::new(JobQueue::new()))
+            .app_data(web::Data::new(None::<AchDirectory>))
+            .app_data(web::Data::new(InputConfig::default()))
+            .app_data(web::Data::new(ReportLinks::new(
+                std::env::temp_dir(),
+                "k",
+                std::time::Duration::from_secs(60),
+            )))
+            .app_data(web::Data::new(Users::default()))
+    }
+
+    /// A session of `name`, a ledger user with `roles`.
+    fn sign_in(store: &Store, name: &str, roles: &[&str]) -> Session {
+        store.add_user(name, "unused").unwrap();
+        store.set_roles(name, roles).unwrap();
+        let session = Session::new();
+        store.create_session(&session.key(), name, 3600).unwrap();
+        session
+    }
+
+    fn rows() -> Vec<Row> {
+        let buf = std::fs::read_to_string("data/onerow.xml").unwrap();
+        ifdohtem::xml_parser::parse_xml(&buf).unwrap().row
+    }
+
+    /// Batch `id` of one row, uploaded by `uploader` and waiting for
+    /// approval.
+    fn pending_batch(store: &Store, id: &str, uploader: &str) {
+        store.create_batch(id, &rows()).unwrap();
+        store.stage_upload(id, "upload session", uploader).unwrap();
+        assert!(store.submit_upload(id, "upload session", uploader).unwrap());
+    }
+
+    /// A form post from `session`, with its CSRF token.
+    fn post(uri: &str, session: &Session, fields: &[(&str, &str)]) -> test::TestRequest {
+        let token = session.csrf_token();
+        let mut form = vec![("csrf_token", token.as_str())];
+        form.extend_from_slice(fields);
+        test::TestRequest::post()
+            .uri(uri)
+            .cookie(session.cookie())
+            .set_form(form)
+    }
+
+    #[actix_web::test]
+    async fn test_forms_need_the_csrf_token() {
+        let store = web::Data::new(Store::open_in_memory().unwrap());
+        let app = test::init_service(test_app(&store)).await;
+        let ava = sign_in(&store, "ava", &["approver", "admin"]);
+        pending_batch(&store, "b1", "ursula");
+        store.create_batch("b2", &rows()).unwrap();
+        store
+            .set_batch_status("b2", BatchStatus::Interrupted)
+            .unwrap();
+
+        let uris = [
+            "/payouts/batches/b1/approve",
+            "/payouts/batches/b2/resume",
+            "/logout",
+        ];
+        for uri in uris {
+            // no body at all, another session's token, and a wrong one
+            let requests = [
+                test::TestRequest::post().uri(uri).cookie(ava.cookie()),
+                post(uri, &Session::new(), &[]).cookie(ava.cookie()),
+                test::TestRequest::post()
+                    .uri(uri)
+                    .cookie(ava.cookie())
+                    .set_form([("csrf_token", "00")]),
+            ];
+            for request in requests {
+                let response = test::call_service(&app, request.to_request()).await;
+                assert_eq!(response.status(), StatusCode::FORBIDDEN, "{uri}");
+                let body = test::read_body(response).await;
+                let body = std::str::from_utf8(&body).unwrap();
+                assert!(body.contains("This request was refused"), "{uri}: {body}");
+            }
+        }
+        // none of them went through
+        assert_eq!(
+            store.batch_status("b1").unwrap(),
+            Some(BatchStatus::PendingApproval)
+        );
+        assert_eq!(
+            store.batch_status("b2").unwrap(),
+            Some(BatchStatus::Interrupted)
+        );
+        assert_eq!(
+            store.session_user(&ava.key()).unwrap().as_deref(),
+            Some("ava")
+        );
+
+        let response = test::call_service(&app, post("/logout", &ava, &[]).to_request()).await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(store.session_user(&ava.key()).unwrap(), None);
+    }
+
+    #[actix_web::test]
+    async fn test_roles_and_ownership() {
+        let store = web::Data::new(Store::open_in_memory().unwrap());
+        let app = test::init_service(test_app(&store)).await;
+        let ursula = sign_in(&store, "ursula", &["uploader"]);
+        let uma = sign_in(&store, "uma", &["uploader", "approver"]);
+        let otto = sign_in(&store, "otto", &["auditor"]);
+        let ava = sign_in(&store, "ava", &["approver"]);
+        pending_batch(&store, "b1", "ursula");
+        pending_batch(&store, "b2", "uma");
+
+        let status = |request: test::TestRequest| {
+            let app = &app;
+            async move { test::call_service(app, request.to_request()).await.status() }
+        };
+        let get = |uri: &str, session: &Session| {
+            test::TestRequest::get().uri(uri).cookie(session.cookie())
+        };
+
+        // approving and rejecting need the approver role
+        let approve = "/payouts/batches/b1/approve";
+        let reject = "/payouts/batches/b1/reject";
+        assert_eq!(
+            status(post(approve, &ursula, &[])).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(post(reject, &ursula, &[])).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(post(reject, &otto, &[])).await,
+            StatusCode::FORBIDDEN
+        );
+        // and never by the batch's uploader, whatever their roles
+        let own = post("/payouts/batches/b2/approve", &uma, &[]).to_request();
+        let response = test::call_service(&app, own).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body = test::read_body(response).await;
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains("You uploaded this batch"));
+        for batch in ["b1", "b2"] {
+            assert_eq!(
+                store.batch_status(batch).unwrap(),
+                Some(BatchStatus::PendingApproval)
+            );
+        }
+
+        // the approvals list is only for approvers
+        let list = "/payouts/approvals";
+        assert_eq!(status(get(list, &ursula)).await, StatusCode::FORBIDDEN);
+        assert_eq!(status(get(list, &otto)).await, StatusCode::FORBIDDEN);
+        assert_eq!(status(get(list, &ava)).await, StatusCode::OK);
+
+        // a dry run needs the uploader or approver role
+        let request = post("/payouts/dry_run", &otto, &[("upload_id", "b1")]);
+        assert_eq!(status(request).await, StatusCode::FORBIDDEN);
+
+        // uploaders see their own batches, reviewers any
+        let page = "/payouts/batches/b1";
+        assert_eq!(status(get(page, &ursula)).await, StatusCode::OK);
+        assert_eq!(status(get(page, &uma)).await, StatusCode::OK);
+        assert_eq!(status(get(page, &otto)).await, StatusCode::OK);
+        let other = sign_in(&store, "olga", &["uploader"]);
+        assert_eq!(status(get(page, &other)).await, StatusCode::FORBIDDEN);
+
+        // and so their jobs, dry runs included
+        let request = post("/payouts/dry_run", &ava, &[("upload_id", "b1")]);
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let job = response
+            .headers()
+            .get("Location")
+            .unwrap()
+            .to_str()
+            .unwrap();
+        let json = format!("{job}/status");
+        for uri in [job, json.as_str()] {
+            assert_eq!(status(get(uri, &ursula)).await, StatusCode::OK, "{uri}");
+            assert_eq!(status(get(uri, &otto)).await, StatusCode::OK, "{uri}");
+            assert_eq!(
+                status(get(uri, &other)).await,
+                StatusCode::FORBIDDEN,
+                "{uri}"
+            );
+        }
+        let unknown = "/payouts/jobs/no-such-job";
+        assert_eq!(status(get(unknown, &ursula)).await, StatusCode::NOT_FOUND);
+
+        // roles are looked up on every request, not kept from sign in
+        assert_eq!(status(get(page, &otto)).await, StatusCode::OK);
+        store.set_roles("otto", &[]).unwrap();
+        assert_eq!(status(get(page, &otto)).await, StatusCode::FORBIDDEN);
+        // and a session signed out elsewhere is gone
+        store.delete_session(&otto.key()).unwrap();
+        assert_eq!(status(get(page, &otto)).await, StatusCode::SEE_OTHER);
+
+        let response = status(post(reject, &ava, &[])).await;
+        assert_eq!(response, StatusCode::SEE_OTHER);
+        assert_eq!(
+            store.batch_status("b1").unwrap(),
+            Some(BatchStatus::Rejected)
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_routes_need_signing_in() {
+        let store = web::Data::new(Store::open_in_memory().unwrap());
+        let app = test::init_service(test_app(&store)).await;
+        pending_batch(&store, "b1", "ursula");
+
+        // every route `app` registers
+        let routes = [
+            (Method::GET, "/health"),
+            (Method::GET, "/login"),
+            (Method::POST, "/login"),
+            (Method::POST, "/logout"),
+            (Method::POST, "/payouts"),
+            (Method::GET, "/payouts"),
+            (Method::POST, "/payouts/submit"),
+            (Method::POST, "/payouts/dry_run"),
+            (Method::GET, "/payouts/jobs/1"),
+            (Method::GET, "/payouts/jobs/1/status"),
+            (Method::POST, "/payouts/cancel_payment"),
+            (Method::GET, "/payouts/approvals"),
+            (Method::GET, "/payouts/batches"),
+            (Method::GET, "/payouts/batches/b1"),
+            (Method::POST, "/payouts/batches/b1/approve"),
+            (Method::POST, "/payouts/batches/b1/reject"),
+            (Method::GET, "/payouts/interrupted"),
+            (Method::POST, "/payouts/batches/b1/resume"),
+            (Method::POST, "/payouts/batches/b1/abandon"),
+            (Method::GET, "/admin/users"),
+            (Method::POST, "/admin/users/ursula/roles"),
+            (Method::GET, "/reports/r1/payments?expires=1&sig=00"),
+            (Method::GET, "/metrics/rate_limit"),
+        ];
+        // with no session cookie, and with one the ledger does not know
+        for session in [None, Some(Session::new())] {
+            for (method, uri) in &routes {
+                let mut request = test::TestRequest::default().method(method.clone()).uri(uri);
+                if let Some(session) = &session {
+                    request = request.cookie(session.cookie());
+                }
+                let response = test::call_service(&app, request.to_request()).await;
+                let path = uri.split('?').next().unwrap();
+                match (method, PUBLIC_PATHS.contains(&path)) {
+                    // refused for its CSRF token, not for being signed out
+                    (&Method::POST, true) => {
+                        assert_eq!(response.status(), StatusCode::FORBIDDEN, "{uri}")
+                    }
+                    (_, true) => assert_eq!(response.status(), StatusCode::OK, "{uri}"),
+                    (&Method::GET, false) => {
+                        assert_eq!(response.status(), StatusCode::SEE_OTHER, "{uri}");
+                        assert_eq!(
+                            response.headers().get("Location").unwrap(),
+                            "/login",
+                            "{uri}"
+                        );
+                    }
+                    _ => assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{uri}"),
+                }
+                assert_eq!(
+                    response.headers().get("X-Frame-Options").unwrap(),
+                    "DENY",
+                    "{method} {uri}"
+                );
+            }
+        }
+        assert_eq!(
+            store.batch_status("b1").unwrap(),
+            Some(BatchStatus::PendingApproval)
+        );
+    }
+}
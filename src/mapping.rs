@@ -0,0 +1,270 @@
+#![doc = r"how the fields of a payout file map onto `Row`"]
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+use crate::input::{Element, RowSource, FIELDS};
+
+/// Where each of the [`FIELDS`] comes from, by field:
+///
+/// ```toml
+/// [fields."Employee/FirstName"]
+/// source = "Employee/FullName"
+/// transforms = ["trim", "first_name"]
+///
+/// [fields."Employee/LastName"]
+/// source = "Employee/FullName"
+/// transforms = ["trim", "last_name"]
+///
+/// [fields."Payor/Address/State"]
+/// default = "IA"
+/// transforms = ["trim", "uppercase"]
+/// ```
+///
+/// A field not listed is read as is from the element, key or column of its
+/// own name.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct Mapping {
+    fields: BTreeMap<String, FieldMapping>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FieldMapping {
+    /// The element path (XML, JSON) or column header (CSV) to read.
+    /// Defaults to the field's own name.
+    pub source: Option<String>,
+    /// Used when the source is absent or blank.
+    pub default: Option<String>,
+    /// Applied in order, after the default.
+    #[serde(default)]
+    pub transforms: Vec<Transform>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transform {
+    Trim,
+    Uppercase,
+    Lowercase,
+    /// The given names of a full name: `Mary Ann` of `Mary Ann Smith` or
+    /// `Smith, Mary Ann`.
+    FirstName,
+    /// The surname of a full name: `Smith` of `Mary Ann Smith` or
+    /// `Smith, Mary Ann`.
+    LastName,
+    /// Reformat a date between patterns made of `YYYY`, `MM` and `DD`, e.g.
+    /// `{ date = { from = "YYYY-MM-DD", to = "MM-DD-YYYY" } }`. A value not
+    /// matching `from` is left as is, for validation to report.
+    Date {
+        from: String,
+        to: String,
+    },
+}
+
+impl Transform {
+    pub fn apply(&self, value: &str) -> String {
+        match self {
+            Transform::Trim => value.trim().to_string(),
+            Transform::Uppercase => value.to_uppercase(),
+            Transform::Lowercase => value.to_lowercase(),
+            Transform::FirstName => split_name(value).0,
+            Transform::LastName => split_name(value).1,
+            Transform::Date { from, to } => match parse_date(from, value) {
+                Some((year, month, day)) => to
+                    .replace("YYYY", &year)
+                    .replace("MM", &month)
+                    .replace("DD", &day),
+                None => value.to_string(),
+            },
+        }
+    }
+}
+
+/// `(given names, surname)`.
+fn split_name(name: &str) -> (String, String) {
+    if let Some((last, first)) = name.split_once(',') {
+        return (first.trim().to_string(), last.trim().to_string());
+    }
+    let name = name.trim();
+    match name.rsplit_once(char::is_whitespace) {
+        Some((first, last)) => (first.trim().to_string(), last.to_string()),
+        None => (name.to_string(), String::new()),
+    }
+}
+
+/// `(YYYY, MM, DD)` of `value`, read per `pattern`.
+fn parse_date(pattern: &str, value: &str) -> Option<(String, String, String)> {
+    let (mut year, mut month, mut day) = (None, None, None);
+    let (mut pattern, mut value) = (pattern, value.trim());
+    while !pattern.is_empty() {
+        let (slot, width) = if pattern.starts_with("YYYY") {
+            (&mut year, 4)
+        } else if pattern.starts_with("MM") {
+            (&mut month, 2)
+        } else if pattern.starts_with("DD") {
+            (&mut day, 2)
+        } else {
+            let c = pattern.chars().next()?;
+            pattern = &pattern[c.len_utf8()..];
+            value = value.strip_prefix(c)?;
+            continue;
+        };
+
+        let digits = value.get(..width)?;
+        if !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        *slot = Some(digits.to_string());
+        pattern = &pattern[width..];
+        value = &value[width..];
+    }
+    if !value.is_empty() {
+        return None;
+    }
+    Some((year?, month?, day?))
+}
+
+impl Mapping {
+    /// Reject fields that do not exist and date patterns that cannot work.
+    pub fn check(&self) -> Result<(), String> {
+        for (field, mapping) in &self.fields {
+            if !FIELDS.contains(&field.as_str()) {
+                return Err(format!("unknown field {field:?}"));
+            }
+            for transform in &mapping.transforms {
+                if let Transform::Date { from, to } = transform {
+                    for pattern in [from, to] {
+                        if ["YYYY", "MM", "DD"]
+                            .iter()
+                            .any(|token| pattern.matches(token).count() != 1)
+                        {
+                            return Err(format!(
+                                "date pattern {pattern:?} of {field:?} needs YYYY, MM and DD once each"
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The [`FIELDS`] of a row read by its source paths or columns.
+    pub fn apply(&self, raw: &RowSource) -> RowSource {
+        let mut elements = Vec::new();
+        for &field in FIELDS {
+            let mapping = self.fields.get(field);
+            let source = mapping.and_then(|m| m.source.as_deref()).unwrap_or(field);
+            let default = mapping.and_then(|m| m.default.as_ref());
+
+            let (text, position) = match (raw.element(source), default) {
+                (Some(e), Some(default)) if e.text.trim().is_empty() => {
+                    (default.clone(), e.position)
+                }
+                (Some(e), _) => (e.text.clone(), e.position),
+                (None, Some(default)) => (default.clone(), raw.position),
+                (None, None) => continue,
+            };
+            let text = mapping
+                .map(|m| &m.transforms[..])
+                .unwrap_or_default()
+                .iter()
+                .fold(text, |text, t| t.apply(&text));
+
+            elements.push(Element {
+                path: field.to_string(),
+                text,
+                position,
+            });
+        }
+        RowSource {
+            position: raw.position,
+            elements,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{open, Format, InputConfig};
+
+    #[test]
+    fn test_transforms() {
+        assert_eq!(
+            split_name("Mary Ann Smith"),
+            ("Mary Ann".into(), "Smith".into())
+        );
+        assert_eq!(
+            split_name(" Smith, Mary Ann "),
+            ("Mary Ann".into(), "Smith".into())
+        );
+        assert_eq!(split_name("Cher"), ("Cher".into(), "".into()));
+
+        let date = Transform::Date {
+            from: "YYYY/MM/DD".into(),
+            to: "MM-DD-YYYY".into(),
+        };
+        assert_eq!(date.apply("1997/11/29"), "11-29-1997");
+        assert_eq!(date.apply("1997-11-29"), "1997-11-29");
+        assert_eq!(date.apply("1997/11/29 00:00"), "1997/11/29 00:00");
+
+        assert!(InputConfig::parse(
+            r#"
+            [fields."Employee/DOB"]
+            transforms = [{ date = { from = "YYYY", to = "MM-DD-YYYY" } }]
+            "#
+        )
+        .is_err());
+        assert!(InputConfig::parse("[fields.Nope]\nsource = \"x\"").is_err());
+    }
+
+    #[test]
+    fn test_mapped_xml_layout() {
+        let buf = std::fs::read_to_string("data/onerow.xml").unwrap();
+        let expected = crate::xml_parser::parse_xml(&buf).unwrap().row;
+
+        // another client's layout of the same row
+        let other = buf
+            .replace("root>", "payouts>")
+            .replace("row>", "payout>")
+            .replace(
+                "<FirstName>Rebekah</FirstName>\n      <LastName>Homenick</LastName>",
+                "<FullName>  Homenick, Rebekah </FullName>",
+            )
+            .replace("11-29-1997", "1997-11-29")
+            .replace("<State>IA</State>", "<State> ia</State>")
+            .replace("<DBA>Dunkin' Donuts</DBA>", "<DBA></DBA>");
+        let config = InputConfig::parse(
+            r#"
+            row = "payout"
+
+            [fields."Employee/FirstName"]
+            source = "Employee/FullName"
+            transforms = ["first_name"]
+
+            [fields."Employee/LastName"]
+            source = "Employee/FullName"
+            transforms = ["last_name"]
+
+            [fields."Employee/DOB"]
+            transforms = [{ date = { from = "YYYY-MM-DD", to = "MM-DD-YYYY" } }]
+
+            [fields."Payor/Address/State"]
+            transforms = ["trim", "uppercase"]
+
+            [fields."Payor/DBA"]
+            default = "Dunkin' Donuts"
+            "#,
+        )
+        .unwrap();
+
+        let rows = open(Format::Xml, other.as_bytes(), &config)
+            .collect::<crate::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(rows, expected);
+    }
+}
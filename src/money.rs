@@ -0,0 +1,229 @@
+#![doc = r"fixed point money amounts"]
+
+use std::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Currency {
+    #[default]
+    Usd,
+}
+
+impl Currency {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Currency::Usd => "USD",
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Currency::Usd => "$",
+        }
+    }
+}
+
+/// An amount in the minor unit (cents) of its currency.
+///
+/// Displays and serializes as plain `1234.56`; [`Money::parse`] also takes
+/// `$1,234.56` and `USD 1234.56`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Money {
+    cents: i64,
+    currency: Currency,
+}
+
+impl Money {
+    pub fn from_cents(cents: i64, currency: Currency) -> Self {
+        Self { cents, currency }
+    }
+
+    pub fn usd(cents: i64) -> Self {
+        Self::from_cents(cents, Currency::Usd)
+    }
+
+    pub fn cents(&self) -> i64 {
+        self.cents
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    pub fn is_positive(&self) -> bool {
+        self.cents > 0
+    }
+
+    /// `None` on overflow or mixed currencies.
+    pub fn checked_add(self, other: Money) -> Option<Money> {
+        if self.currency != other.currency {
+            return None;
+        }
+        Some(Self::from_cents(
+            self.cents.checked_add(other.cents)?,
+            self.currency,
+        ))
+    }
+
+    /// Parse an amount such as `$1,234.56`. Rejects anything negative,
+    /// malformed, or with more than two decimal places.
+    pub fn parse(s: &str) -> Result<Money> {
+        let invalid = |reason: &str| Error::validation("amount", format!("{s:?} {reason}"));
+
+        let mut rest = s.trim();
+        if rest.starts_with('-') || rest.starts_with('(') {
+            return Err(invalid("is negative"));
+        }
+
+        let currency = Currency::Usd;
+        if let Some(r) = rest.strip_prefix(currency.symbol()) {
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix(currency.code()) {
+            rest = r.trim_start();
+        } else if rest.starts_with(|c: char| !c.is_ascii_digit()) {
+            return Err(invalid("has an unsupported currency"));
+        }
+        if rest.starts_with('-') {
+            return Err(invalid("is negative"));
+        }
+
+        let (whole, fraction) = match rest.split_once('.') {
+            Some((whole, fraction)) => (whole, Some(fraction)),
+            None => (rest, None),
+        };
+
+        if whole.is_empty() {
+            return Err(invalid("has no whole part"));
+        }
+        if whole.contains(',') {
+            let mut groups = whole.split(',');
+            let first = groups.next().unwrap_or_default();
+            if first.is_empty() || first.len() > 3 || groups.any(|g| g.len() != 3) {
+                return Err(invalid("has misplaced thousands separators"));
+            }
+        }
+        let whole = whole.replace(',', "");
+        if !whole.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid("is not a number"));
+        }
+
+        let fraction_cents = match fraction {
+            None => 0,
+            Some(f) if f.is_empty() || !f.chars().all(|c| c.is_ascii_digit()) => {
+                return Err(invalid("is not a number"))
+            }
+            Some(f) if f.len() > 2 => return Err(invalid("has more than two decimal places")),
+            Some(f) if f.len() == 1 => f.parse::<i64>().unwrap_or_default() * 10,
+            Some(f) => f.parse::<i64>().unwrap_or_default(),
+        };
+
+        let cents = whole
+            .parse::<i64>()
+            .ok()
+            .and_then(|w| w.checked_mul(100))
+            .and_then(|w| w.checked_add(fraction_cents))
+            .ok_or_else(|| invalid("is too large"))?;
+
+        Ok(Self::from_cents(cents, currency))
+    }
+
+    /// [`Money::parse`], also rejecting zero. What every payment amount
+    /// must pass.
+    pub fn parse_amount(s: &str) -> Result<Money> {
+        let money = Self::parse(s)?;
+        if !money.is_positive() {
+            return Err(Error::validation("amount", format!("{s:?} is zero")));
+        }
+        Ok(money)
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.cents < 0 { "-" } else { "" };
+        let abs = self.cents.unsigned_abs();
+        write!(f, "{sign}{}.{:02}", abs / 100, abs % 100)
+    }
+}
+
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Zero still deserializes, so a zero amount fails its own row instead of
+/// the whole file.
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Money::parse(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Serde helper for the integer cents Method uses, e.g.
+/// `#[serde(with = "crate::money::usd_cents")]`.
+pub mod usd_cents {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        money: &Money,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_i64(money.cents())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Money, D::Error> {
+        Ok(Money::usd(i64::deserialize(deserializer)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Money::parse("$4.34").unwrap().cents(), 434);
+        assert_eq!(Money::parse("$1,234.56").unwrap().cents(), 123456);
+        assert_eq!(Money::parse(" USD 1234.5 ").unwrap().cents(), 123450);
+        assert_eq!(Money::parse("12").unwrap().cents(), 1200);
+        assert_eq!(Money::parse("$0.00").unwrap().cents(), 0);
+
+        for bad in [
+            "",
+            "$",
+            "abc",
+            "$1.234",
+            "$-1.00",
+            "-$1.00",
+            "($1.00)",
+            "$1,23.00",
+            "$,123",
+            "1.",
+            "$1.2.3",
+            "€4.00",
+            "$99999999999999999999",
+        ] {
+            assert!(Money::parse(bad).is_err(), "{bad:?} should not parse");
+        }
+
+        assert!(Money::parse_amount("$0.00").is_err());
+        assert!(Money::parse_amount("$0.01").is_ok());
+    }
+
+    #[test]
+    fn test_display_and_add() {
+        let a = Money::parse("$1,234.56").unwrap();
+        let b = Money::parse("$0.44").unwrap();
+        assert_eq!(a.to_string(), "1234.56");
+        assert_eq!(a.checked_add(b).unwrap().to_string(), "1235.00");
+        assert_eq!(Money::usd(5).to_string(), "0.05");
+        assert_eq!(Money::usd(i64::MAX).checked_add(Money::usd(1)), None);
+    }
+}
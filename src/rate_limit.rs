@@ -0,0 +1,333 @@
+#![doc = r"process wide rate limiter for Method calls"]
+
+use std::{
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use reqwest::header::HeaderMap;
+use serde::Serialize;
+
+/// Method allows 600 requests per minute by default.
+pub const DEFAULT_REQUESTS_PER_MINUTE: u32 = 600;
+
+/// The longest Method can hold requests for, whatever its headers say, so
+/// a bogus value cannot stall every job in the process.
+pub const MAX_PAUSE: Duration = Duration::from_secs(5 * 60);
+
+/// `X-RateLimit-Reset` values above this are Unix times rather than
+/// seconds from now.
+const MAX_RESET_SECS: u64 = 24 * 60 * 60;
+
+/// How long to wait for an `X-RateLimit-Reset` of `reset`: seconds from
+/// now, or a Unix time, which is how some servers send it. At most
+/// [`MAX_PAUSE`].
+pub fn reset_delay(reset: u64, now: SystemTime) -> Duration {
+    let secs = match reset > MAX_RESET_SECS {
+        true => {
+            let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            reset.saturating_sub(now)
+        }
+        false => reset,
+    };
+    Duration::from_secs(secs).min(MAX_PAUSE)
+}
+
+/// A `Retry-After` value, in seconds or as an HTTP date, as a wait from
+/// `now`. At most [`MAX_PAUSE`].
+pub fn retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    let wait = match value.parse() {
+        Ok(secs) => Duration::from_secs(secs),
+        Err(_) => httpdate::parse_http_date(value)
+            .ok()?
+            .duration_since(now)
+            .unwrap_or_default(),
+    };
+    Some(wait.min(MAX_PAUSE))
+}
+
+/// Token bucket shared by every [`MethodClient`](crate::caller::MethodClient).
+///
+/// On top of the local budget it follows the `X-RateLimit-Remaining` /
+/// `X-RateLimit-Reset` headers and `Retry-After` from Method, so several
+/// processes sharing one token still back off together.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    last_refill: Instant,
+    remote_limit: Option<u64>,
+    remote_remaining: Option<u64>,
+    /// No request goes out before this.
+    paused_until: Option<Instant>,
+    waiting: u64,
+    total_acquired: u64,
+}
+
+/// Snapshot of the limiter, for monitoring.
+#[derive(Debug, Clone, Serialize)]
+pub struct RateLimitState {
+    pub capacity: u32,
+    pub available: f64,
+    pub refill_per_sec: f64,
+    pub remote_limit: Option<u64>,
+    pub remote_remaining: Option<u64>,
+    pub paused_for_ms: Option<u64>,
+    pub waiting: u64,
+    pub total_acquired: u64,
+}
+
+static GLOBAL: OnceLock<Arc<RateLimiter>> = OnceLock::new();
+
+impl RateLimiter {
+    /// Allow `capacity` requests per `per`, with bursts up to `capacity`.
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` or `per` is zero, which would never let a request
+    /// out.
+    pub fn new(capacity: u32, per: Duration) -> Self {
+        assert!(capacity > 0, "a rate limit allows at least one request");
+        assert!(!per.is_zero(), "a rate limit is over a nonzero period");
+        Self {
+            bucket: Mutex::new(Bucket {
+                capacity: capacity as f64,
+                tokens: capacity as f64,
+                refill_per_sec: capacity as f64 / per.as_secs_f64(),
+                last_refill: Instant::now(),
+                remote_limit: None,
+                remote_remaining: None,
+                paused_until: None,
+                waiting: 0,
+                total_acquired: 0,
+            }),
+        }
+    }
+
+    /// The process wide limiter. Its budget is read from
+    /// `METHOD_RATE_LIMIT` (requests per minute) on first use.
+    pub fn global() -> Arc<RateLimiter> {
+        GLOBAL
+            .get_or_init(|| {
+                let per_minute = std::env::var("METHOD_RATE_LIMIT")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .filter(|&n| n > 0)
+                    .unwrap_or(DEFAULT_REQUESTS_PER_MINUTE);
+                Arc::new(RateLimiter::new(per_minute, Duration::from_secs(60)))
+            })
+            .clone()
+    }
+
+    /// Wait until a request may be sent, then take one token.
+    pub async fn acquire(&self) {
+        // counted as waiting until it gets its token or is dropped
+        let mut queued: Option<Waiting> = None;
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                let now = Instant::now();
+                bucket.refill(now);
+
+                let wait = match bucket.paused_until {
+                    Some(until) if until > now => Some(until - now),
+                    _ => {
+                        bucket.paused_until = None;
+                        if bucket.tokens >= 1.0 {
+                            bucket.tokens -= 1.0;
+                            bucket.total_acquired += 1;
+                            if let Some(remaining) = bucket.remote_remaining.as_mut() {
+                                *remaining = remaining.saturating_sub(1);
+                            }
+                            None
+                        } else {
+                            Some(Duration::from_secs_f64(
+                                (1.0 - bucket.tokens) / bucket.refill_per_sec,
+                            ))
+                        }
+                    }
+                };
+                if wait.is_some() && queued.is_none() {
+                    bucket.waiting += 1;
+                    queued = Some(Waiting(&self.bucket));
+                }
+                wait
+            };
+
+            match wait {
+                Some(wait) => tokio::time::sleep(wait).await,
+                None => return,
+            }
+        }
+    }
+
+    /// Hold every request for `duration`, at most [`MAX_PAUSE`], e.g.
+    /// after a 429.
+    pub fn pause(&self, duration: Duration) {
+        if let Some(until) = Instant::now().checked_add(duration.min(MAX_PAUSE)) {
+            self.bucket.lock().unwrap().pause_until(until);
+        }
+    }
+
+    /// Adapt to the rate limit headers of a Method response.
+    pub fn observe(&self, headers: &HeaderMap) {
+        let header = |name: &str| -> Option<u64> {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse().ok())
+        };
+
+        if header("x-ratelimit-remaining") == Some(0) {
+            if let Some(reset) = header("x-ratelimit-reset") {
+                self.pause(reset_delay(reset, SystemTime::now()));
+            }
+        }
+
+        let mut bucket = self.bucket.lock().unwrap();
+        if let Some(limit) = header("x-ratelimit-limit") {
+            bucket.remote_limit = Some(limit);
+        }
+        if let Some(remaining) = header("x-ratelimit-remaining") {
+            bucket.remote_remaining = Some(remaining);
+            // never believe we have more budget than Method says
+            bucket.tokens = bucket.tokens.min(remaining as f64);
+        }
+    }
+
+    pub fn state(&self) -> RateLimitState {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        bucket.refill(now);
+        RateLimitState {
+            capacity: bucket.capacity as u32,
+            available: bucket.tokens,
+            refill_per_sec: bucket.refill_per_sec,
+            remote_limit: bucket.remote_limit,
+            remote_remaining: bucket.remote_remaining,
+            paused_for_ms: bucket
+                .paused_until
+                .filter(|until| *until > now)
+                .map(|until| (until - now).as_millis() as u64),
+            waiting: bucket.waiting,
+            total_acquired: bucket.total_acquired,
+        }
+    }
+}
+
+/// One request in [`RateLimiter::acquire`]'s queue.
+struct Waiting<'a>(&'a Mutex<Bucket>);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().waiting -= 1;
+    }
+}
+
+impl Bucket {
+    fn pause_until(&mut self, until: Instant) {
+        if self.paused_until.is_none_or(|p| p < until) {
+            self.paused_until = Some(until);
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_acquire_waits_for_refill() {
+        let limiter = RateLimiter::new(2, Duration::from_millis(200));
+        let start = Instant::now();
+        for _ in 0..3 {
+            limiter.acquire().await;
+        }
+        // the third token needs 100ms of refill
+        assert!(start.elapsed() >= Duration::from_millis(90));
+        assert_eq!(limiter.state().total_acquired, 3);
+        assert_eq!(limiter.state().waiting, 0);
+    }
+
+    #[test]
+    fn test_observe_headers() {
+        let limiter = RateLimiter::new(600, Duration::from_secs(60));
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-limit", "600".parse().unwrap());
+        headers.insert("x-ratelimit-remaining", "0".parse().unwrap());
+        headers.insert("x-ratelimit-reset", "30".parse().unwrap());
+        limiter.observe(&headers);
+
+        let state = limiter.state();
+        assert_eq!(state.remote_limit, Some(600));
+        assert_eq!(state.remote_remaining, Some(0));
+        assert!(state.available < 1.0);
+        assert!(state.paused_for_ms.unwrap() > 29_000);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_acquire_stops_waiting() {
+        let limiter = RateLimiter::new(1, Duration::from_secs(60));
+        limiter.acquire().await;
+
+        let mut queued = Box::pin(limiter.acquire());
+        assert!(futures_util::poll!(&mut queued).is_pending());
+        assert_eq!(limiter.state().waiting, 1);
+        // e.g. a cancelled job
+        drop(queued);
+        assert_eq!(limiter.state().waiting, 0);
+
+        let timed_out = tokio::time::timeout(Duration::from_millis(10), limiter.acquire());
+        assert!(timed_out.await.is_err());
+        assert_eq!(limiter.state().waiting, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one request")]
+    fn test_zero_rate_limit_is_refused() {
+        RateLimiter::new(0, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_bogus_resets_are_capped() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(reset_delay(30, now), Duration::from_secs(30));
+        // Unix times, in the future and already passed
+        assert_eq!(reset_delay(1_700_000_045, now), Duration::from_secs(45));
+        assert_eq!(reset_delay(1_699_999_990, now), Duration::ZERO);
+        assert_eq!(reset_delay(1_800_000_000, now), MAX_PAUSE);
+        assert_eq!(reset_delay(u64::MAX, now), MAX_PAUSE);
+
+        assert_eq!(retry_after(" 12 ", now), Some(Duration::from_secs(12)));
+        assert_eq!(retry_after(&u64::MAX.to_string(), now), Some(MAX_PAUSE));
+        let date = httpdate::fmt_http_date(now + Duration::from_secs(90));
+        assert_eq!(retry_after(&date, now), Some(Duration::from_secs(90)));
+        let date = httpdate::fmt_http_date(now - Duration::from_secs(90));
+        assert_eq!(retry_after(&date, now), Some(Duration::ZERO));
+        assert_eq!(retry_after("soon", now), None);
+
+        // neither panics nor stalls the process
+        let limiter = RateLimiter::new(600, Duration::from_secs(60));
+        limiter.pause(Duration::MAX);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-remaining", "0".parse().unwrap());
+        headers.insert("x-ratelimit-reset", u64::MAX.to_string().parse().unwrap());
+        limiter.observe(&headers);
+        assert!(limiter.state().paused_for_ms.unwrap() <= MAX_PAUSE.as_millis() as u64);
+    }
+}
@@ -0,0 +1,286 @@
+#![doc = r"report files and the signed, expiring links they are downloaded through"]
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+use crate::{from_hex, to_hex};
+
+/// Report kinds, as in `{report_id}_{kind}.csv`, with their button labels.
+pub const REPORT_KINDS: &[(&str, &str)] = &[
+    ("a", "Report1"),
+    ("b", "Report2"),
+    ("c", "Report3"),
+    ("d", "Failed Rows"),
+];
+
+/// Default report directory, relative to the working directory.
+pub const DEFAULT_REPORT_DIR: &str = "tmp";
+
+/// How long a download link works when `REPORT_LINK_TTL` is not set.
+pub const DEFAULT_LINK_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// The longest a download link works; a longer `REPORT_LINK_TTL` is cut
+/// down to this.
+pub const MAX_LINK_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Why a download link was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkError {
+    /// Not a report id and kind this service ever hands out.
+    Malformed,
+    BadSignature,
+    Expired,
+}
+
+impl LinkError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LinkError::Malformed => "malformed",
+            LinkError::BadSignature => "bad signature",
+            LinkError::Expired => "expired",
+        }
+    }
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LinkError::Malformed => "no such report",
+            LinkError::BadSignature => "the download link is not valid",
+            LinkError::Expired => {
+                "the download link has expired, reload the job page for a new one"
+            }
+        })
+    }
+}
+
+/// Where reports are written, and the links they are served through:
+/// `/reports/{report_id}/{kind}?expires={unix time}&sig={hmac}`, where the
+/// HMAC-SHA256 covers the id, the kind and the expiry. Only the file a
+/// valid link names is ever read, and its path is built from the checked
+/// id and kind, never from the request.
+#[derive(Clone)]
+pub struct ReportLinks {
+    dir: PathBuf,
+    key: Vec<u8>,
+    ttl: Duration,
+}
+
+impl fmt::Debug for ReportLinks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReportLinks")
+            .field("dir", &self.dir)
+            .field("ttl", &self.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Report ids are uuids, `simulated-` prefixed for dry runs; see
+/// [`write_reports`](crate::jobs::write_reports).
+pub fn is_report_id(id: &str) -> bool {
+    let uuid = id.strip_prefix("simulated-").unwrap_or(id);
+    uuid::Uuid::try_parse(uuid).is_ok_and(|u| u.hyphenated().to_string() == uuid)
+}
+
+impl ReportLinks {
+    pub fn new(dir: impl Into<PathBuf>, key: impl Into<Vec<u8>>, ttl: Duration) -> Self {
+        Self {
+            dir: dir.into(),
+            key: key.into(),
+            ttl: ttl.min(MAX_LINK_TTL),
+        }
+    }
+
+    /// Build from the environment, creating the report directory:
+    ///
+    /// - `IFDOHTEM_REPORTS`: defaults to [`DEFAULT_REPORT_DIR`]
+    /// - `IFDOHTEM_SIGNING_KEY`: the HMAC key. Without it a random one is
+    ///   made, and links stop working when the process restarts.
+    /// - `REPORT_LINK_TTL`: seconds, defaults to [`DEFAULT_LINK_TTL`] and
+    ///   at most [`MAX_LINK_TTL`]
+    pub fn from_env() -> std::io::Result<Self> {
+        let dir = std::env::var("IFDOHTEM_REPORTS").unwrap_or_else(|_| DEFAULT_REPORT_DIR.into());
+        std::fs::create_dir_all(&dir)?;
+        let dir = std::fs::canonicalize(dir)?;
+
+        let key = match std::env::var("IFDOHTEM_SIGNING_KEY") {
+            Ok(key) if !key.is_empty() => key.into_bytes(),
+            _ => {
+                tracing::warn!(
+                    "IFDOHTEM_SIGNING_KEY is not set, download links will not survive a restart"
+                );
+                let mut key = vec![0; 32];
+                rand::thread_rng().fill_bytes(&mut key);
+                key
+            }
+        };
+        let ttl = std::env::var("REPORT_LINK_TTL")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_LINK_TTL);
+        if ttl > MAX_LINK_TTL {
+            tracing::warn!(
+                "REPORT_LINK_TTL is over {} seconds, using that",
+                MAX_LINK_TTL.as_secs()
+            );
+        }
+
+        Ok(Self::new(dir, key, ttl))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn mac(&self, report_id: &str, kind: &str, expires: u64) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("any key length works");
+        mac.update(format!("{report_id}\n{kind}\n{expires}").as_bytes());
+        mac
+    }
+
+    /// A link to report `kind` of `report_id`, valid for the configured
+    /// time from now.
+    pub fn url(&self, report_id: &str, kind: &str) -> String {
+        self.url_at(report_id, kind, unix_now())
+    }
+
+    fn url_at(&self, report_id: &str, kind: &str, now: u64) -> String {
+        let expires = now.saturating_add(self.ttl.as_secs());
+        let sig = to_hex(&self.mac(report_id, kind, expires).finalize().into_bytes());
+        format!("/reports/{report_id}/{kind}?expires={expires}&sig={sig}")
+    }
+
+    /// The file a link names, if the link is genuine and still valid.
+    pub fn verify(
+        &self,
+        report_id: &str,
+        kind: &str,
+        expires: u64,
+        sig: &str,
+    ) -> Result<PathBuf, LinkError> {
+        self.verify_at(report_id, kind, expires, sig, unix_now())
+    }
+
+    fn verify_at(
+        &self,
+        report_id: &str,
+        kind: &str,
+        expires: u64,
+        sig: &str,
+        now: u64,
+    ) -> Result<PathBuf, LinkError> {
+        if !is_report_id(report_id) || !REPORT_KINDS.iter().any(|(k, _)| *k == kind) {
+            return Err(LinkError::Malformed);
+        }
+        let sig = from_hex(sig).ok_or(LinkError::BadSignature)?;
+        self.mac(report_id, kind, expires)
+            .verify_slice(&sig)
+            .map_err(|_| LinkError::BadSignature)?;
+        if expires < now {
+            return Err(LinkError::Expired);
+        }
+        Ok(self.dir.join(format!("{report_id}_{kind}.csv")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(url: &str) -> (String, String, u64, String) {
+        let (path, query) = url.split_once('?').unwrap();
+        let mut parts = path.trim_start_matches("/reports/").split('/');
+        let (id, kind) = (parts.next().unwrap(), parts.next().unwrap());
+        let (expires, sig) = query.split_once('&').unwrap();
+        (
+            id.to_string(),
+            kind.to_string(),
+            expires.trim_start_matches("expires=").parse().unwrap(),
+            sig.trim_start_matches("sig=").to_string(),
+        )
+    }
+
+    #[test]
+    fn test_signed_links() {
+        let links = ReportLinks::new("/srv/reports", "secret", Duration::from_secs(60));
+        let id = "simulated-67e55044-10b1-426f-9247-bb680e5fe0c8";
+        let (report_id, kind, expires, sig) = query(&links.url_at(id, "d", 1_000));
+        assert_eq!(
+            (report_id.as_str(), kind.as_str(), expires),
+            (id, "d", 1_060)
+        );
+
+        assert_eq!(
+            links.verify_at(id, "d", expires, &sig, 1_060),
+            Ok(PathBuf::from(format!("/srv/reports/{id}_d.csv")))
+        );
+        assert_eq!(
+            links.verify_at(id, "d", expires, &sig, 1_061),
+            Err(LinkError::Expired)
+        );
+        // a later expiry, another report or kind, or another key
+        assert_eq!(
+            links.verify_at(id, "d", expires + 3_600, &sig, 1_000),
+            Err(LinkError::BadSignature)
+        );
+        assert_eq!(
+            links.verify_at(id, "a", expires, &sig, 1_000),
+            Err(LinkError::BadSignature)
+        );
+        let other = ReportLinks::new("/srv/reports", "other", Duration::from_secs(60));
+        assert_eq!(
+            other.verify_at(id, "d", expires, &sig, 1_000),
+            Err(LinkError::BadSignature)
+        );
+        assert_eq!(
+            links.verify_at(id, "d", expires, "zz", 1_000),
+            Err(LinkError::BadSignature)
+        );
+
+        for (id, kind) in [
+            ("../../etc/passwd", "d"),
+            ("67e55044-10b1-426f-9247-bb680e5fe0c8/..", "d"),
+            ("{67e55044-10b1-426f-9247-bb680e5fe0c8}", "d"),
+            ("67e55044-10b1-426f-9247-bb680e5fe0c8", "e"),
+            ("67e55044-10b1-426f-9247-bb680e5fe0c8", "d.csv/../x"),
+        ] {
+            // even signed
+            let sig = to_hex(&links.mac(id, kind, 1_060).finalize().into_bytes());
+            assert_eq!(
+                links.verify_at(id, kind, 1_060, &sig, 1_000),
+                Err(LinkError::Malformed)
+            );
+        }
+    }
+
+    #[test]
+    fn test_long_ttl_is_capped() {
+        let id = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+        let links = ReportLinks::new("/srv/reports", "secret", Duration::from_secs(u64::MAX));
+        let (_, _, expires, sig) = query(&links.url_at(id, "a", 1_000));
+        assert_eq!(expires, 1_000 + MAX_LINK_TTL.as_secs());
+        assert!(links.verify_at(id, "a", expires, &sig, 1_000).is_ok());
+
+        // a clock near the end of time still gives a working link
+        let (_, _, expires, sig) = query(&links.url_at(id, "a", u64::MAX - 1));
+        assert_eq!(expires, u64::MAX);
+        assert!(links
+            .verify_at(id, "a", expires, &sig, u64::MAX - 1)
+            .is_ok());
+    }
+}
@@ -0,0 +1,65 @@
+#![doc = r"retry policy for Method calls"]
+
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Exponential backoff with full jitter.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Total attempts, including the first one.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Never retry.
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Upper bound of the wait before retry number `attempt` (starting at 1).
+    pub fn ceiling(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(2_u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay)
+    }
+
+    /// Wait before retry number `attempt`, picked uniformly in `[0, ceiling]`.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self.ceiling(attempt);
+        Duration::from_millis(rand::thread_rng().gen_range(0..=ceiling.as_millis() as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_ceiling() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.ceiling(1), Duration::from_millis(500));
+        assert_eq!(policy.ceiling(2), Duration::from_secs(1));
+        assert_eq!(policy.ceiling(4), Duration::from_secs(4));
+        assert_eq!(policy.ceiling(20), Duration::from_secs(30));
+
+        for attempt in 1..10 {
+            assert!(policy.backoff(attempt) <= policy.ceiling(attempt));
+        }
+    }
+}
@@ -0,0 +1,109 @@
+#![doc = r"browser sessions, identified by an opaque cookie"]
+
+use actix_web::{
+    cookie::{Cookie, SameSite},
+    HttpRequest,
+};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::{from_hex, to_hex};
+
+/// Name of the session cookie.
+pub const COOKIE: &str = "ifdohtem_session";
+
+/// A session, by the random token its cookie carries. Sessions are made
+/// at sign in, so a token set before it is never reused after. The sign-in
+/// form gets one too, unknown to the ledger, for its CSRF token.
+#[derive(Debug, Clone)]
+pub struct Session {
+    token: String,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        let mut token = [0; 32];
+        rand::thread_rng().fill_bytes(&mut token);
+        Session {
+            token: to_hex(&token),
+        }
+    }
+
+    /// The session of `req`'s cookie, if it has one.
+    pub fn existing(req: &HttpRequest) -> Option<Self> {
+        let token = req.cookie(COOKIE)?.value().to_string();
+        (!token.is_empty()).then_some(Session { token })
+    }
+
+    /// What the ledger keeps to tell sessions apart: the SHA-256 of the
+    /// token, so the database alone does not give a session away.
+    pub fn key(&self) -> String {
+        to_hex(&Sha256::digest(self.token.as_bytes()))
+    }
+
+    fn csrf_mac(&self) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(self.token.as_bytes()).expect("any key length works");
+        mac.update(b"csrf");
+        mac
+    }
+
+    /// The token every form of the session posts back. Only pages served
+    /// to the session show it, and it cannot be worked out without the
+    /// cookie, which other sites can neither read nor make the browser send.
+    pub fn csrf_token(&self) -> String {
+        to_hex(&self.csrf_mac().finalize().into_bytes())
+    }
+
+    pub fn verify_csrf(&self, token: &str) -> bool {
+        from_hex(token).is_some_and(|token| self.csrf_mac().verify_slice(&token).is_ok())
+    }
+
+    /// The cookie carrying the session, only sent over HTTPS (or to
+    /// localhost) and kept from scripts and other sites.
+    pub fn cookie(&self) -> Cookie<'static> {
+        Cookie::build(COOKIE, self.token.clone())
+            .path("/")
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::Strict)
+            .finish()
+    }
+
+    /// Clears the session cookie, at sign out.
+    pub fn removal_cookie() -> Cookie<'static> {
+        let mut cookie = Cookie::build(COOKIE, "")
+            .path("/")
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::Strict)
+            .finish();
+        cookie.make_removal();
+        cookie
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csrf_token() {
+        let session = Session::new();
+        let token = session.csrf_token();
+        assert!(session.verify_csrf(&token));
+        assert_ne!(token, session.key());
+
+        assert!(!Session::new().verify_csrf(&token));
+        assert!(!session.verify_csrf(""));
+        assert!(!session.verify_csrf("not hex"));
+        assert!(!session.verify_csrf(&token[..32]));
+    }
+}
@@ -0,0 +1,1460 @@
+#![doc = r"sqlite ledger of batches, rows and the Method objects created for them"]
+
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, MutexGuard},
+};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{
+    caller::{MethodError, Payment, PaymentStatus},
+    money::Money,
+    xml_parser::Row,
+    Error, Reports, Result,
+};
+
+/// Default database location, relative to the working directory.
+pub const DEFAULT_DB_PATH: &str = "data/ifdohtem.sqlite3";
+
+/// Rows [`Store::import_batch`] writes per transaction.
+pub const IMPORT_CHUNK_ROWS: usize = 1_000;
+
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS batches (
+    id          TEXT PRIMARY KEY,
+    status      TEXT NOT NULL,
+    row_count   INTEGER NOT NULL,
+    created_at  TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    updated_at  TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+);
+
+CREATE TABLE IF NOT EXISTS batch_rows (
+    batch_id    TEXT NOT NULL REFERENCES batches(id),
+    row_index   INTEGER NOT NULL,
+    employee_id TEXT NOT NULL,
+    payor_id    TEXT NOT NULL,
+    branch      TEXT NOT NULL,
+    amount      TEXT NOT NULL,
+    status      TEXT NOT NULL,
+    error       TEXT,
+    -- the row itself, so a batch can be resumed without the upload
+    data        TEXT NOT NULL,
+    PRIMARY KEY (batch_id, row_index)
+);
+
+-- every Method object created for a row, keyed by pipeline step
+CREATE TABLE IF NOT EXISTS method_objects (
+    batch_id    TEXT NOT NULL,
+    row_index   INTEGER NOT NULL,
+    step        TEXT NOT NULL,
+    method_id   TEXT NOT NULL,
+    created_at  TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    PRIMARY KEY (batch_id, row_index, step)
+);
+
+CREATE TABLE IF NOT EXISTS payments (
+    id                          TEXT PRIMARY KEY,
+    batch_id                    TEXT NOT NULL,
+    row_index                   INTEGER NOT NULL,
+    source                      TEXT NOT NULL,
+    destination                 TEXT NOT NULL,
+    amount                      INTEGER NOT NULL,
+    description                 TEXT NOT NULL,
+    status                      TEXT NOT NULL,
+    error                       TEXT,
+    estimated_completion_date   TEXT,
+    created_at                  TEXT NOT NULL,
+    updated_at                  TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS transitions (
+    id          INTEGER PRIMARY KEY AUTOINCREMENT,
+    batch_id    TEXT NOT NULL,
+    row_index   INTEGER,
+    from_status TEXT,
+    to_status   TEXT NOT NULL,
+    at          TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+);
+"#;
+
+/// Applied in order on top of [`SCHEMA`]; `PRAGMA user_version` counts how
+/// many already ran. Only ever append.
+const MIGRATIONS: &[&str] = &[
+    // step a failed row stopped at
+    "ALTER TABLE batch_rows ADD COLUMN failed_step TEXT;",
+    // Method objects shared by rows and batches, see `Store::shared_object`
+    r#"
+    CREATE TABLE shared_objects (
+        scope       TEXT NOT NULL,
+        step        TEXT NOT NULL,
+        key         TEXT NOT NULL,
+        method_id   TEXT NOT NULL,
+        created_at  TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+        PRIMARY KEY (scope, step, key)
+    );
+    "#,
+    // audit trail of report downloads, refused ones included
+    r#"
+    CREATE TABLE downloads (
+        id          INTEGER PRIMARY KEY AUTOINCREMENT,
+        report_id   TEXT NOT NULL,
+        kind        TEXT NOT NULL,
+        client      TEXT,
+        outcome     TEXT NOT NULL,
+        at          TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+    );
+    "#,
+    // the session an upload belongs to, and when it was confirmed
+    r#"
+    ALTER TABLE batches ADD COLUMN session TEXT;
+    ALTER TABLE batches ADD COLUMN confirmed_at TEXT;
+    "#,
+    // users who sign in, their sessions, and who did what
+    r#"
+    CREATE TABLE users (
+        name            TEXT PRIMARY KEY,
+        password_hash   TEXT NOT NULL,
+        created_at      TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+    );
+    CREATE TABLE sessions (
+        key         TEXT PRIMARY KEY,
+        user_name   TEXT NOT NULL,
+        created_at  TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+        expires_at  TEXT NOT NULL
+    );
+    ALTER TABLE batches ADD COLUMN uploaded_by TEXT;
+    ALTER TABLE batches ADD COLUMN confirmed_by TEXT;
+    ALTER TABLE downloads ADD COLUMN user_name TEXT;
+    "#,
+    // roles, and the approval of batches by someone other than the uploader
+    r#"
+    CREATE TABLE user_roles (
+        user_name   TEXT NOT NULL,
+        role        TEXT NOT NULL,
+        PRIMARY KEY (user_name, role)
+    );
+    ALTER TABLE batches RENAME COLUMN confirmed_at TO submitted_at;
+    ALTER TABLE batches RENAME COLUMN confirmed_by TO reviewed_by;
+    ALTER TABLE batches ADD COLUMN reviewed_at TEXT;
+    ALTER TABLE transitions ADD COLUMN user_name TEXT;
+    "#,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchStatus {
+    /// Its rows are still being recorded.
+    Importing,
+    Uploaded,
+    /// Submitted by the uploader, waiting for an approver.
+    PendingApproval,
+    /// Approved for execution, not running yet.
+    Approved,
+    /// Turned down by an approver.
+    Rejected,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+    /// Was running when the process stopped.
+    Interrupted,
+    /// Interrupted, and the operator chose not to resume it.
+    Abandoned,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RowStatus {
+    Pending,
+    Running,
+    Succeeded,
+    Failed,
+    /// Never attempted, because the batch stopped first.
+    Skipped,
+}
+
+macro_rules! status_strings {
+    ($ty:ty { $($variant:ident => $s:literal),* $(,)? }) => {
+        impl $ty {
+            pub fn as_str(&self) -> &'static str {
+                match self {
+                    $(Self::$variant => $s,)*
+                }
+            }
+
+            pub fn parse(s: &str) -> Option<Self> {
+                match s {
+                    $($s => Some(Self::$variant),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+status_strings!(BatchStatus {
+    Importing => "importing",
+    Uploaded => "uploaded",
+    PendingApproval => "pending_approval",
+    Approved => "approved",
+    Rejected => "rejected",
+    Running => "running",
+    Completed => "completed",
+    Failed => "failed",
+    Cancelled => "cancelled",
+    Interrupted => "interrupted",
+    Abandoned => "abandoned",
+});
+
+status_strings!(RowStatus {
+    Pending => "pending",
+    Running => "running",
+    Succeeded => "succeeded",
+    Failed => "failed",
+    Skipped => "skipped",
+});
+
+/// How a row ended, with what is needed to fix and resubmit it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowOutcome {
+    pub row: usize,
+    pub status: RowStatus,
+    pub step: Option<String>,
+    pub reason: Option<String>,
+    pub data: Row,
+}
+
+/// A batch, without its rows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchSummary {
+    pub id: String,
+    pub status: BatchStatus,
+    pub row_count: usize,
+    pub uploaded_by: Option<String>,
+    /// Who approved or rejected it.
+    pub reviewed_by: Option<String>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+/// A change of a batch's status.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transition {
+    pub from: Option<BatchStatus>,
+    pub to: BatchStatus,
+    /// Who made it, if a user did rather than the service.
+    pub user: Option<String>,
+    pub at: String,
+}
+
+const BATCH_SUMMARY_COLUMNS: &str =
+    "id, status, row_count, uploaded_by, reviewed_by, created_at, updated_at";
+
+fn batch_summary(r: &rusqlite::Row) -> rusqlite::Result<BatchSummary> {
+    Ok(BatchSummary {
+        id: r.get(0)?,
+        status: BatchStatus::parse(&r.get::<_, String>(1)?).unwrap_or(BatchStatus::Uploaded),
+        row_count: r.get(2)?,
+        uploaded_by: r.get(3)?,
+        reviewed_by: r.get(4)?,
+        created_at: r.get(5)?,
+        updated_at: r.get(6)?,
+    })
+}
+
+/// One attempt to download a report.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Download {
+    pub report_id: String,
+    pub kind: String,
+    /// Address of whoever asked.
+    pub client: Option<String>,
+    pub user: Option<String>,
+    /// `served`, or why it was refused.
+    pub outcome: String,
+    pub at: String,
+}
+
+/// The ledger. Writes that belong together commit in one transaction: a
+/// batch or row changing status and its `transitions` row, or a user's
+/// roles. A batch is imported in chunks, and only becomes
+/// [`Uploaded`](BatchStatus::Uploaded) with the last one, so the file stays
+/// consistent if the process dies at any point.
+#[derive(Debug)]
+pub struct Store {
+    conn: Mutex<Connection>,
+}
+
+impl Store {
+    pub fn open(path: &str) -> Result<Self> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    /// `IFDOHTEM_DB`, defaulting to [`DEFAULT_DB_PATH`].
+    pub fn open_from_env() -> Result<Self> {
+        Self::open(&std::env::var("IFDOHTEM_DB").unwrap_or_else(|_| DEFAULT_DB_PATH.to_string()))
+    }
+
+    pub fn open_in_memory() -> Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    /// A private ledger in a temporary file, paged to disk rather than
+    /// held in memory, and deleted when the store is dropped.
+    pub fn open_temporary() -> Result<Self> {
+        // SQLite's name for such a database
+        Self::open("")
+    }
+
+    fn with_connection(conn: Connection) -> Result<Self> {
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute_batch(SCHEMA)?;
+        let version: usize = conn.pragma_query_value(None, "user_version", |r| r.get(0))?;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            conn.execute_batch(migration)?;
+            conn.pragma_update(None, "user_version", i + 1)?;
+        }
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap()
+    }
+
+    /// Record an uploaded batch and its rows. Does nothing if the batch
+    /// is already known.
+    pub fn create_batch(&self, batch_id: &str, rows: &[Row]) -> Result<()> {
+        self.import_batch(batch_id, rows.iter().cloned().map(Ok::<_, Error>))?;
+        Ok(())
+    }
+
+    /// Record a batch from a stream of rows, one row in memory at a time,
+    /// and return its row count. Nothing is recorded if the stream fails.
+    /// Does nothing if the batch is already known.
+    ///
+    /// The rows go in [`IMPORT_CHUNK_ROWS`] at a time, each chunk in its own
+    /// transaction, and the ledger is only locked while a chunk is written,
+    /// never while the stream is read, so running jobs carry on meanwhile.
+    /// Until the last chunk is in, the batch is [`BatchStatus::Importing`],
+    /// which nothing runs; a failed import is deleted, and one the process
+    /// died in is by [`Store::discard_incomplete_imports`].
+    pub fn import_batch<E>(
+        &self,
+        batch_id: &str,
+        rows: impl IntoIterator<Item = std::result::Result<Row, E>>,
+    ) -> Result<usize>
+    where
+        Error: From<E>,
+    {
+        let inserted = self.conn().execute(
+            "INSERT OR IGNORE INTO batches (id, status, row_count) VALUES (?1, ?2, 0)",
+            params![batch_id, BatchStatus::Importing.as_str()],
+        )?;
+        if inserted == 0 {
+            return self.row_count(batch_id);
+        }
+
+        match self.import_rows(batch_id, rows) {
+            Ok(count) => Ok(count),
+            Err(e) => {
+                self.delete_batch(batch_id)?;
+                Err(e)
+            }
+        }
+    }
+
+    fn import_rows<E>(
+        &self,
+        batch_id: &str,
+        rows: impl IntoIterator<Item = std::result::Result<Row, E>>,
+    ) -> Result<usize>
+    where
+        Error: From<E>,
+    {
+        let mut rows = rows.into_iter();
+        let mut count = 0;
+        loop {
+            // read the chunk before taking the lock
+            let chunk = rows
+                .by_ref()
+                .take(IMPORT_CHUNK_ROWS)
+                .map(|row| Ok(row?))
+                .collect::<Result<Vec<Row>>>()?;
+            if chunk.is_empty() {
+                break;
+            }
+
+            let mut conn = self.conn();
+            let tx = conn.transaction()?;
+            {
+                let mut insert = tx.prepare(
+                    "INSERT INTO batch_rows
+                     (batch_id, row_index, employee_id, payor_id, branch, amount, status, data)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                )?;
+                for row in &chunk {
+                    insert.execute(params![
+                        batch_id,
+                        count,
+                        row.employee.dunkin_id,
+                        row.payor.dunkin_id,
+                        row.employee.dunkin_branch,
+                        row.amount.to_string(),
+                        RowStatus::Pending.as_str(),
+                        serde_json::to_string(row)?,
+                    ])?;
+                    count += 1;
+                }
+            }
+            tx.commit()?;
+        }
+
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "UPDATE batches SET row_count = ?2, status = ?3,
+             updated_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now') WHERE id = ?1",
+            params![batch_id, count, BatchStatus::Uploaded.as_str()],
+        )?;
+        tx.execute(
+            "INSERT INTO transitions (batch_id, to_status) VALUES (?1, ?2)",
+            params![batch_id, BatchStatus::Uploaded.as_str()],
+        )?;
+        tx.commit()?;
+        Ok(count)
+    }
+
+    fn delete_batch(&self, batch_id: &str) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM batch_rows WHERE batch_id = ?1", [batch_id])?;
+        tx.execute("DELETE FROM transitions WHERE batch_id = ?1", [batch_id])?;
+        tx.execute("DELETE FROM batches WHERE id = ?1", [batch_id])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Delete the batches whose import the previous process did not finish.
+    /// Only call this on startup, before any upload.
+    pub fn discard_incomplete_imports(&self) -> Result<Vec<String>> {
+        let ids = self.batches_with_status(BatchStatus::Importing)?;
+        for id in &ids {
+            self.delete_batch(id)?;
+        }
+        Ok(ids)
+    }
+
+    /// Bind a just imported batch to the session, and so the user, that
+    /// uploaded it. Only that session can then submit or cancel it.
+    pub fn stage_upload(&self, batch_id: &str, session: &str, user: &str) -> Result<()> {
+        self.conn().execute(
+            "UPDATE batches SET session = ?2, uploaded_by = ?3 WHERE id = ?1 AND session IS NULL",
+            params![batch_id, session, user],
+        )?;
+        Ok(())
+    }
+
+    /// Whether `batch_id` is an upload of `session` still waiting to be
+    /// submitted or cancelled.
+    pub fn is_staged(&self, batch_id: &str, session: &str) -> Result<bool> {
+        Ok(self
+            .conn()
+            .query_row(
+                "SELECT 1 FROM batches WHERE id = ?1 AND session = ?2 AND status = ?3",
+                params![batch_id, session, BatchStatus::Uploaded.as_str()],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some())
+    }
+
+    /// Move `batch_id` from `from` to `to` on behalf of `user`, if it is in
+    /// `from` and `condition` holds, and record the transition. `condition`
+    /// and `set` (extra assignments) may refer to the id as `?1` and the
+    /// user as `?4`, then to `args` from `?5` on. Returns whether it moved,
+    /// so two requests racing for the same batch cannot both win.
+    #[allow(clippy::too_many_arguments)]
+    fn move_batch(
+        &self,
+        batch_id: &str,
+        from: BatchStatus,
+        to: BatchStatus,
+        user: &str,
+        condition: &str,
+        set: &str,
+        args: &[&str],
+    ) -> Result<bool> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let params = [batch_id, from.as_str(), to.as_str(), user]
+            .into_iter()
+            .chain(args.iter().copied());
+        let moved = {
+            let mut stmt = tx.prepare(&format!(
+                "UPDATE batches SET status = ?3,
+                 updated_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now'){set}
+                 WHERE id = ?1 AND status = ?2 AND ({condition})"
+            ))?;
+            // SQLite refuses more parameters than the statement refers to
+            let count = stmt.parameter_count();
+            stmt.execute(rusqlite::params_from_iter(params.take(count)))?
+        };
+        if moved == 1 {
+            tx.execute(
+                "INSERT INTO transitions (batch_id, from_status, to_status, user_name)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![batch_id, from.as_str(), to.as_str(), user],
+            )?;
+        }
+        tx.commit()?;
+        Ok(moved == 1)
+    }
+
+    /// Submit a staged upload of `session` for approval. Returns false if
+    /// it is not one, so a batch is only ever submitted once.
+    pub fn submit_upload(&self, batch_id: &str, session: &str, user: &str) -> Result<bool> {
+        self.move_batch(
+            batch_id,
+            BatchStatus::Uploaded,
+            BatchStatus::PendingApproval,
+            user,
+            "session = ?5",
+            ", submitted_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now')",
+            &[session],
+        )
+    }
+
+    /// Approve a batch waiting for approval, unless `user` uploaded it.
+    /// Returns false if it is not waiting, or is `user`'s own: whoever
+    /// uploads a batch can never release it.
+    pub fn approve_batch(&self, batch_id: &str, user: &str) -> Result<bool> {
+        self.move_batch(
+            batch_id,
+            BatchStatus::PendingApproval,
+            BatchStatus::Approved,
+            user,
+            "uploaded_by IS NOT NULL AND uploaded_by <> ?4",
+            ", reviewed_by = ?4, reviewed_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now')",
+            &[],
+        )
+    }
+
+    /// Turn down a batch waiting for approval. Returns false if it is not
+    /// waiting.
+    pub fn reject_batch(&self, batch_id: &str, user: &str) -> Result<bool> {
+        self.move_batch(
+            batch_id,
+            BatchStatus::PendingApproval,
+            BatchStatus::Rejected,
+            user,
+            "1",
+            ", reviewed_by = ?4, reviewed_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now')",
+            &[],
+        )
+    }
+
+    /// Claim an interrupted batch for running it again. Returns false if it
+    /// is not one, so it is only ever resumed by one job.
+    pub fn resume_batch(&self, batch_id: &str, user: &str) -> Result<bool> {
+        self.move_batch(
+            batch_id,
+            BatchStatus::Interrupted,
+            BatchStatus::Running,
+            user,
+            "1",
+            "",
+            &[],
+        )
+    }
+
+    /// Move a batch that was just approved or resumed, `from`, on to `to`
+    /// when its job could not be started, so it does not look like it is
+    /// running. Returns false if it moved on meanwhile.
+    pub fn job_not_started(
+        &self,
+        batch_id: &str,
+        from: BatchStatus,
+        to: BatchStatus,
+        user: &str,
+    ) -> Result<bool> {
+        self.move_batch(batch_id, from, to, user, "1", "", &[])
+    }
+
+    /// Give up on an interrupted batch. Returns false if it is not one.
+    pub fn abandon_batch(&self, batch_id: &str, user: &str) -> Result<bool> {
+        self.move_batch(
+            batch_id,
+            BatchStatus::Interrupted,
+            BatchStatus::Abandoned,
+            user,
+            "1",
+            "",
+            &[],
+        )
+    }
+
+    pub fn batch(&self, batch_id: &str) -> Result<Option<BatchSummary>> {
+        Ok(self
+            .conn()
+            .query_row(
+                &format!("SELECT {BATCH_SUMMARY_COLUMNS} FROM batches WHERE id = ?1"),
+                [batch_id],
+                batch_summary,
+            )
+            .optional()?)
+    }
+
+    /// The latest `limit` batches, or only those in `status`, newest first.
+    pub fn batches(&self, status: Option<BatchStatus>, limit: usize) -> Result<Vec<BatchSummary>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {BATCH_SUMMARY_COLUMNS} FROM batches
+             WHERE ?1 IS NULL OR status = ?1 ORDER BY created_at DESC, id LIMIT ?2"
+        ))?;
+        let batches = stmt
+            .query_map(params![status.map(|s| s.as_str()), limit], batch_summary)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(batches)
+    }
+
+    /// Every status `batch_id` went through, oldest first.
+    pub fn transitions(&self, batch_id: &str) -> Result<Vec<Transition>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT from_status, to_status, user_name, at FROM transitions
+             WHERE batch_id = ?1 AND row_index IS NULL ORDER BY id",
+        )?;
+        let transitions = stmt
+            .query_map([batch_id], |r| {
+                Ok(Transition {
+                    from: r
+                        .get::<_, Option<String>>(0)?
+                        .as_deref()
+                        .and_then(BatchStatus::parse),
+                    to: BatchStatus::parse(&r.get::<_, String>(1)?)
+                        .unwrap_or(BatchStatus::Uploaded),
+                    user: r.get(2)?,
+                    at: r.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(transitions)
+    }
+
+    /// Add a user who signs in with a password, or change their password.
+    /// Their roles are kept.
+    pub fn add_user(&self, name: &str, password_hash: &str) -> Result<()> {
+        self.conn().execute(
+            "INSERT INTO users (name, password_hash) VALUES (?1, ?2)
+             ON CONFLICT (name) DO UPDATE SET password_hash = excluded.password_hash",
+            params![name, password_hash],
+        )?;
+        Ok(())
+    }
+
+    pub fn password_hash(&self, name: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn()
+            .query_row(
+                "SELECT password_hash FROM users WHERE name = ?1",
+                [name],
+                |r| r.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Users of the ledger (not of the users file), by name.
+    pub fn users(&self) -> Result<Vec<String>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT name FROM users ORDER BY name")?;
+        let names = stmt
+            .query_map([], |r| r.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(names)
+    }
+
+    pub fn roles(&self, name: &str) -> Result<Vec<String>> {
+        let conn = self.conn();
+        let mut stmt =
+            conn.prepare("SELECT role FROM user_roles WHERE user_name = ?1 ORDER BY role")?;
+        let roles = stmt
+            .query_map([name], |r| r.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(roles)
+    }
+
+    /// Replace the roles of user `name`.
+    pub fn set_roles(&self, name: &str, roles: &[&str]) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM user_roles WHERE user_name = ?1", [name])?;
+        for role in roles {
+            tx.execute(
+                "INSERT OR IGNORE INTO user_roles (user_name, role) VALUES (?1, ?2)",
+                params![name, role],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Sign `user` in under session `key` for `ttl_secs` seconds.
+    pub fn create_session(&self, key: &str, user: &str, ttl_secs: u64) -> Result<()> {
+        let conn = self.conn();
+        conn.execute(
+            "DELETE FROM sessions WHERE expires_at < strftime('%Y-%m-%dT%H:%M:%fZ', 'now')",
+            [],
+        )?;
+        conn.execute(
+            "INSERT INTO sessions (key, user_name, expires_at)
+             VALUES (?1, ?2, strftime('%Y-%m-%dT%H:%M:%fZ', 'now', ?3))",
+            params![key, user, format!("+{ttl_secs} seconds")],
+        )?;
+        Ok(())
+    }
+
+    /// The user signed in under session `key`, unless it expired.
+    pub fn session_user(&self, key: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn()
+            .query_row(
+                "SELECT user_name FROM sessions
+                 WHERE key = ?1 AND expires_at > strftime('%Y-%m-%dT%H:%M:%fZ', 'now')",
+                [key],
+                |r| r.get(0),
+            )
+            .optional()?)
+    }
+
+    pub fn delete_session(&self, key: &str) -> Result<()> {
+        self.conn()
+            .execute("DELETE FROM sessions WHERE key = ?1", [key])?;
+        Ok(())
+    }
+
+    /// Cancel a staged upload of `session`. Returns false if it is not one.
+    pub fn cancel_upload(&self, batch_id: &str, session: &str, user: &str) -> Result<bool> {
+        self.move_batch(
+            batch_id,
+            BatchStatus::Uploaded,
+            BatchStatus::Cancelled,
+            user,
+            "session = ?5",
+            "",
+            &[session],
+        )
+    }
+
+    pub fn row_count(&self, batch_id: &str) -> Result<usize> {
+        Ok(self
+            .conn()
+            .query_row(
+                "SELECT row_count FROM batches WHERE id = ?1",
+                [batch_id],
+                |r| r.get(0),
+            )
+            .optional()?
+            .unwrap_or_default())
+    }
+
+    pub fn batch_row(&self, batch_id: &str, row: usize) -> Result<Option<Row>> {
+        let data: Option<String> = self
+            .conn()
+            .query_row(
+                "SELECT data FROM batch_rows WHERE batch_id = ?1 AND row_index = ?2",
+                params![batch_id, row],
+                |r| r.get(0),
+            )
+            .optional()?;
+        Ok(data.map(|d| serde_json::from_str(&d)).transpose()?)
+    }
+
+    pub fn batch_status(&self, batch_id: &str) -> Result<Option<BatchStatus>> {
+        let status: Option<String> = self
+            .conn()
+            .query_row(
+                "SELECT status FROM batches WHERE id = ?1",
+                [batch_id],
+                |r| r.get(0),
+            )
+            .optional()?;
+        Ok(status.as_deref().and_then(BatchStatus::parse))
+    }
+
+    pub fn set_batch_status(&self, batch_id: &str, status: BatchStatus) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO transitions (batch_id, from_status, to_status)
+             SELECT id, status, ?2 FROM batches WHERE id = ?1",
+            params![batch_id, status.as_str()],
+        )?;
+        tx.execute(
+            "UPDATE batches SET status = ?2,
+             updated_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now') WHERE id = ?1",
+            params![batch_id, status.as_str()],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Ids of the batches in `status`, oldest first.
+    pub fn batches_with_status(&self, status: BatchStatus) -> Result<Vec<String>> {
+        let conn = self.conn();
+        let mut stmt =
+            conn.prepare("SELECT id FROM batches WHERE status = ?1 ORDER BY created_at")?;
+        let ids = stmt
+            .query_map([status.as_str()], |r| r.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(ids)
+    }
+
+    /// Flag every batch still marked running, or approved but never
+    /// started, as interrupted. Only call this on startup, before any job
+    /// runs.
+    pub fn mark_interrupted(&self) -> Result<Vec<String>> {
+        let mut ids = self.batches_with_status(BatchStatus::Running)?;
+        ids.extend(self.batches_with_status(BatchStatus::Approved)?);
+        for id in &ids {
+            self.set_batch_status(id, BatchStatus::Interrupted)?;
+        }
+        Ok(ids)
+    }
+
+    pub fn row_status(&self, batch_id: &str, row: usize) -> Result<Option<RowStatus>> {
+        let status: Option<String> = self
+            .conn()
+            .query_row(
+                "SELECT status FROM batch_rows WHERE batch_id = ?1 AND row_index = ?2",
+                params![batch_id, row],
+                |r| r.get(0),
+            )
+            .optional()?;
+        Ok(status.as_deref().and_then(RowStatus::parse))
+    }
+
+    pub fn set_row_status(
+        &self,
+        batch_id: &str,
+        row: usize,
+        status: RowStatus,
+        error: Option<&str>,
+    ) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO transitions (batch_id, row_index, from_status, to_status)
+             SELECT batch_id, row_index, status, ?3 FROM batch_rows
+             WHERE batch_id = ?1 AND row_index = ?2",
+            params![batch_id, row, status.as_str()],
+        )?;
+        tx.execute(
+            "UPDATE batch_rows SET status = ?3, error = ?4
+             WHERE batch_id = ?1 AND row_index = ?2",
+            params![batch_id, row, status.as_str(), error],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Mark the row failed at `step`, or skipped when `step` is `None`.
+    pub fn set_row_outcome(
+        &self,
+        batch_id: &str,
+        row: usize,
+        step: Option<&str>,
+        reason: &str,
+    ) -> Result<()> {
+        let status = match step {
+            Some(_) => RowStatus::Failed,
+            None => RowStatus::Skipped,
+        };
+        self.set_row_status(batch_id, row, status, Some(reason))?;
+        self.conn().execute(
+            "UPDATE batch_rows SET failed_step = ?3 WHERE batch_id = ?1 AND row_index = ?2",
+            params![batch_id, row, step],
+        )?;
+        Ok(())
+    }
+
+    /// Rows that did not succeed, in row order.
+    pub fn unsuccessful_rows(&self, batch_id: &str) -> Result<Vec<RowOutcome>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT row_index, status, failed_step, error, data FROM batch_rows
+             WHERE batch_id = ?1 AND status <> ?2 ORDER BY row_index",
+        )?;
+        let rows = stmt
+            .query_map(params![batch_id, RowStatus::Succeeded.as_str()], |r| {
+                Ok((
+                    r.get::<_, usize>(0)?,
+                    r.get::<_, String>(1)?,
+                    r.get::<_, Option<String>>(2)?,
+                    r.get::<_, Option<String>>(3)?,
+                    r.get::<_, String>(4)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        rows.into_iter()
+            .map(|(row, status, step, reason, data)| {
+                Ok(RowOutcome {
+                    row,
+                    status: RowStatus::parse(&status).unwrap_or(RowStatus::Pending),
+                    step,
+                    reason,
+                    data: serde_json::from_str(&data)?,
+                })
+            })
+            .collect()
+    }
+
+    /// Remember the Method object created for `step` of a row.
+    pub fn record_object(
+        &self,
+        batch_id: &str,
+        row: usize,
+        step: &str,
+        method_id: &str,
+    ) -> Result<()> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO method_objects (batch_id, row_index, step, method_id)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![batch_id, row, step, method_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn object_id(&self, batch_id: &str, row: usize, step: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn()
+            .query_row(
+                "SELECT method_id FROM method_objects
+                 WHERE batch_id = ?1 AND row_index = ?2 AND step = ?3",
+                params![batch_id, row, step],
+                |r| r.get(0),
+            )
+            .optional()?)
+    }
+
+    /// The Method object an earlier row or batch created for `step` of
+    /// whatever `key` identifies (an employee, a payor, a bank account), in
+    /// the Method environment `scope`.
+    pub fn shared_object(&self, scope: &str, step: &str, key: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn()
+            .query_row(
+                "SELECT method_id FROM shared_objects
+                 WHERE scope = ?1 AND step = ?2 AND key = ?3",
+                params![scope, step, key],
+                |r| r.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Remember a Method object for reuse. The first one recorded for a key
+    /// is kept.
+    pub fn record_shared_object(
+        &self,
+        scope: &str,
+        step: &str,
+        key: &str,
+        method_id: &str,
+    ) -> Result<()> {
+        self.conn().execute(
+            "INSERT OR IGNORE INTO shared_objects (scope, step, key, method_id)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![scope, step, key, method_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn record_download(
+        &self,
+        report_id: &str,
+        kind: &str,
+        client: Option<&str>,
+        user: Option<&str>,
+        outcome: &str,
+    ) -> Result<()> {
+        self.conn().execute(
+            "INSERT INTO downloads (report_id, kind, client, user_name, outcome)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![report_id, kind, client, user, outcome],
+        )?;
+        Ok(())
+    }
+
+    /// Every download attempt of a report, oldest first.
+    pub fn downloads(&self, report_id: &str) -> Result<Vec<Download>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT report_id, kind, client, user_name, outcome, at FROM downloads
+             WHERE report_id = ?1 ORDER BY id",
+        )?;
+        let downloads = stmt
+            .query_map(params![report_id], |r| {
+                Ok(Download {
+                    report_id: r.get(0)?,
+                    kind: r.get(1)?,
+                    client: r.get(2)?,
+                    user: r.get(3)?,
+                    outcome: r.get(4)?,
+                    at: r.get(5)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(downloads)
+    }
+
+    pub fn record_payment(&self, batch_id: &str, row: usize, payment: &Payment) -> Result<()> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO payments
+             (id, batch_id, row_index, source, destination, amount, description, status,
+              error, estimated_completion_date, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            params![
+                payment.id,
+                batch_id,
+                row,
+                payment.source,
+                payment.destination,
+                payment.amount.cents(),
+                payment.description,
+                payment.status.as_str(),
+                payment
+                    .error
+                    .as_ref()
+                    .map(serde_json::to_string)
+                    .transpose()?,
+                payment.estimated_completion_date,
+                payment.created_at,
+                payment.updated_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Payments of the batch, in row order.
+    pub fn payments(&self, batch_id: &str) -> Result<Vec<Payment>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT id, source, destination, amount, description, status, error,
+                    estimated_completion_date, created_at, updated_at
+             FROM payments WHERE batch_id = ?1 ORDER BY row_index",
+        )?;
+        let rows = stmt.query_map([batch_id], |r| {
+            let status: String = r.get(5)?;
+            let error: Option<String> = r.get(6)?;
+            Ok(Payment {
+                id: r.get(0)?,
+                source: r.get(1)?,
+                destination: r.get(2)?,
+                amount: Money::usd(r.get(3)?),
+                description: r.get(4)?,
+                status: serde_json::from_value(serde_json::Value::String(status))
+                    .unwrap_or(PaymentStatus::Unknown),
+                error: error.and_then(|e| serde_json::from_str::<MethodError>(&e).ok()),
+                estimated_completion_date: r.get(7)?,
+                created_at: r.get(8)?,
+                updated_at: r.get(9)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Sums are done by sqlite on integer cents, so they are exact.
+    fn sum_by(&self, batch_id: &str, sql: &str) -> Result<BTreeMap<String, Money>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt.query_map([batch_id], |r| {
+            Ok((r.get::<_, String>(0)?, Money::usd(r.get(1)?)))
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// The three reports of a batch, built from what the ledger recorded.
+    pub fn reports(&self, batch_id: &str) -> Result<Reports> {
+        // Total amount of funds paid out per unique source account.
+        let report1 = self.sum_by(
+            batch_id,
+            "SELECT source, SUM(amount) FROM payments WHERE batch_id = ?1 GROUP BY source",
+        )?;
+        // Total amount of funds paid out per Dunkin branch.
+        let report2 = self.sum_by(
+            batch_id,
+            "SELECT r.branch, SUM(p.amount) FROM payments p
+             JOIN batch_rows r ON r.batch_id = p.batch_id AND r.row_index = p.row_index
+             WHERE p.batch_id = ?1 GROUP BY r.branch",
+        )?;
+        // The status of every payment and its relevant metadata.
+        let report3 = self.payments(batch_id)?;
+        // Every row that did not succeed, and why.
+        let report4 = self.unsuccessful_rows(batch_id)?;
+        Ok(Reports(report1, report2, report3, report4))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payment(id: &str, source: &str, amount: i64) -> Payment {
+        Payment {
+            id: id.to_string(),
+            source: source.to_string(),
+            destination: "acc_dest".to_string(),
+            amount: Money::usd(amount),
+            description: String::new(),
+            status: PaymentStatus::Pending,
+            error: None,
+            estimated_completion_date: None,
+            created_at: "2024-04-04T16:00:00.000Z".to_string(),
+            updated_at: "2024-04-04T16:00:00.000Z".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_ledger_reports() {
+        let buf = std::fs::read_to_string("data/onerow.xml").unwrap();
+        let row = crate::xml_parser::parse_xml(&buf).unwrap().row.remove(0);
+        let rows = vec![row];
+
+        let store = Store::open_in_memory().unwrap();
+        store.create_batch("b1", &rows).unwrap();
+        // creating again is a no-op
+        store.create_batch("b1", &rows).unwrap();
+        assert_eq!(
+            store.batch_status("b1").unwrap(),
+            Some(BatchStatus::Uploaded)
+        );
+
+        store.set_batch_status("b1", BatchStatus::Running).unwrap();
+        store
+            .record_object("b1", 0, "ach account", "acc_src")
+            .unwrap();
+        assert_eq!(
+            store.object_id("b1", 0, "ach account").unwrap().as_deref(),
+            Some("acc_src")
+        );
+        store
+            .record_payment("b1", 0, &payment("pmt_1", "acc_src", 434))
+            .unwrap();
+        store
+            .set_row_status("b1", 0, RowStatus::Succeeded, None)
+            .unwrap();
+
+        let Reports(a, b, c, d) = store.reports("b1").unwrap();
+        assert_eq!(a.get("acc_src"), Some(&Money::usd(434)));
+        assert_eq!(
+            b.get(&rows[0].employee.dunkin_branch),
+            Some(&Money::usd(434))
+        );
+        assert_eq!(c, vec![payment("pmt_1", "acc_src", 434)]);
+        assert!(d.is_empty());
+    }
+
+    #[test]
+    fn test_open_temporary() {
+        let buf = std::fs::read_to_string("data/onerow.xml").unwrap();
+        let rows = crate::xml_parser::parse_xml(&buf).unwrap().row;
+
+        let store = Store::open_temporary().unwrap();
+        store.create_batch("b1", &rows).unwrap();
+        assert_eq!(store.row_count("b1").unwrap(), 1);
+        // each is its own database
+        let other = Store::open_temporary().unwrap();
+        assert_eq!(other.batch_status("b1").unwrap(), None);
+    }
+
+    #[test]
+    fn test_import_batch_is_all_or_nothing() {
+        let buf = std::fs::read_to_string("data/onerow.xml").unwrap();
+        let row = crate::xml_parser::parse_xml(&buf).unwrap().row.remove(0);
+
+        let store = Store::open_in_memory().unwrap();
+        let rows = vec![Ok(row.clone()), Err(Error::validation("amount", "bad"))];
+        assert!(store.import_batch("b1", rows).is_err());
+        assert_eq!(store.batch_status("b1").unwrap(), None);
+
+        // failing after whole chunks were written leaves nothing either
+        let rows = std::iter::repeat_n(row.clone(), IMPORT_CHUNK_ROWS + 1)
+            .map(Ok)
+            .chain([Err(Error::validation("amount", "bad"))]);
+        assert!(store.import_batch("b1", rows).is_err());
+        assert_eq!(store.batch_status("b1").unwrap(), None);
+        assert!(store.batch_row("b1", 0).unwrap().is_none());
+
+        let rows = vec![Ok::<_, Error>(row.clone()), Ok(row.clone())];
+        assert_eq!(store.import_batch("b1", rows).unwrap(), 2);
+        assert_eq!(store.row_count("b1").unwrap(), 2);
+        assert_eq!(
+            store.batch_status("b1").unwrap(),
+            Some(BatchStatus::Uploaded)
+        );
+
+        let rows = std::iter::repeat_n(row.clone(), IMPORT_CHUNK_ROWS * 2 + 1).map(Ok::<_, Error>);
+        assert_eq!(
+            store.import_batch("b2", rows).unwrap(),
+            IMPORT_CHUNK_ROWS * 2 + 1
+        );
+        assert_eq!(
+            store.batch_row("b2", IMPORT_CHUNK_ROWS * 2).unwrap(),
+            Some(row)
+        );
+
+        // an import the process died in
+        store
+            .conn()
+            .execute(
+                "INSERT INTO batches (id, status, row_count) VALUES ('b3', 'importing', 0)",
+                [],
+            )
+            .unwrap();
+        assert_eq!(store.discard_incomplete_imports().unwrap(), ["b3"]);
+        assert_eq!(store.batch_status("b3").unwrap(), None);
+        assert_eq!(store.row_count("b2").unwrap(), IMPORT_CHUNK_ROWS * 2 + 1);
+    }
+
+    #[test]
+    fn test_row_outcomes() {
+        let buf = std::fs::read_to_string("data/onerow.xml").unwrap();
+        let mut rows = crate::xml_parser::parse_xml(&buf).unwrap().row;
+        rows.push(rows[0].clone());
+        rows.push(rows[0].clone());
+
+        let store = Store::open_in_memory().unwrap();
+        store.create_batch("b1", &rows).unwrap();
+        store
+            .set_row_status("b1", 0, RowStatus::Succeeded, None)
+            .unwrap();
+        store
+            .set_row_outcome("b1", 1, Some("payment"), "bad amount")
+            .unwrap();
+        store
+            .set_row_outcome("b1", 2, None, "batch stopped")
+            .unwrap();
+
+        let outcomes = store.unsuccessful_rows("b1").unwrap();
+        assert_eq!(outcomes.len(), 2);
+        assert_eq!(outcomes[0].row, 1);
+        assert_eq!(outcomes[0].status, RowStatus::Failed);
+        assert_eq!(outcomes[0].step.as_deref(), Some("payment"));
+        assert_eq!(outcomes[0].reason.as_deref(), Some("bad amount"));
+        assert_eq!(outcomes[1].status, RowStatus::Skipped);
+        assert_eq!(outcomes[1].step, None);
+    }
+
+    #[test]
+    fn test_staged_uploads() {
+        let buf = std::fs::read_to_string("data/onerow.xml").unwrap();
+        let rows = crate::xml_parser::parse_xml(&buf).unwrap().row;
+
+        let store = Store::open_in_memory().unwrap();
+        store.create_batch("b1", &rows).unwrap();
+        store.stage_upload("b1", "s1", "alice").unwrap();
+        // the upload stays with the first session
+        store.stage_upload("b1", "s2", "bob").unwrap();
+        assert!(store.is_staged("b1", "s1").unwrap());
+        assert!(!store.is_staged("b1", "s2").unwrap());
+        assert!(!store.is_staged("b2", "s1").unwrap());
+
+        assert!(!store.submit_upload("b1", "s2", "bob").unwrap());
+        assert!(!store.cancel_upload("b1", "s2", "bob").unwrap());
+        // not submitted yet
+        assert!(!store.approve_batch("b1", "bob").unwrap());
+        assert!(store.submit_upload("b1", "s1", "alice").unwrap());
+        assert!(!store.submit_upload("b1", "s1", "alice").unwrap());
+        assert!(!store.cancel_upload("b1", "s1", "alice").unwrap());
+        assert!(!store.is_staged("b1", "s1").unwrap());
+
+        // the uploader cannot approve their own batch, anyone else once
+        assert!(!store.approve_batch("b1", "alice").unwrap());
+        assert!(store.approve_batch("b1", "bob").unwrap());
+        assert!(!store.approve_batch("b1", "carol").unwrap());
+        assert!(!store.reject_batch("b1", "carol").unwrap());
+        let batch = store.batch("b1").unwrap().unwrap();
+        assert_eq!(batch.status, BatchStatus::Approved);
+        assert_eq!(batch.row_count, 1);
+        assert_eq!(batch.uploaded_by.as_deref(), Some("alice"));
+        assert_eq!(batch.reviewed_by.as_deref(), Some("bob"));
+        let transitions: Vec<_> = store
+            .transitions("b1")
+            .unwrap()
+            .into_iter()
+            .map(|t| (t.from, t.to, t.user))
+            .collect();
+        assert_eq!(
+            transitions,
+            [
+                (None, BatchStatus::Uploaded, None),
+                (
+                    Some(BatchStatus::Uploaded),
+                    BatchStatus::PendingApproval,
+                    Some("alice".to_string())
+                ),
+                (
+                    Some(BatchStatus::PendingApproval),
+                    BatchStatus::Approved,
+                    Some("bob".to_string())
+                ),
+            ]
+        );
+
+        store.create_batch("b2", &rows).unwrap();
+        store.stage_upload("b2", "s1", "alice").unwrap();
+        assert!(store.cancel_upload("b2", "s1", "alice").unwrap());
+        assert!(!store.submit_upload("b2", "s1", "alice").unwrap());
+        assert_eq!(
+            store.batch_status("b2").unwrap(),
+            Some(BatchStatus::Cancelled)
+        );
+
+        store.create_batch("b3", &rows).unwrap();
+        store.stage_upload("b3", "s1", "alice").unwrap();
+        assert!(store.submit_upload("b3", "s1", "alice").unwrap());
+        assert!(store.reject_batch("b3", "bob").unwrap());
+        assert!(!store.approve_batch("b3", "carol").unwrap());
+
+        let pending = store
+            .batches(Some(BatchStatus::Rejected), 10)
+            .unwrap()
+            .into_iter()
+            .map(|b| b.id)
+            .collect::<Vec<_>>();
+        assert_eq!(pending, ["b3"]);
+        assert_eq!(store.batches(None, 10).unwrap().len(), 3);
+        assert_eq!(store.batches(None, 2).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_sessions() {
+        let store = Store::open_in_memory().unwrap();
+        store.create_session("k1", "alice", 60).unwrap();
+        store.create_session("k2", "bob", 0).unwrap();
+        assert_eq!(store.session_user("k1").unwrap().as_deref(), Some("alice"));
+        // expired
+        assert_eq!(store.session_user("k2").unwrap(), None);
+        assert_eq!(store.session_user("k3").unwrap(), None);
+        store.delete_session("k1").unwrap();
+        assert_eq!(store.session_user("k1").unwrap(), None);
+
+        store.add_user("carol", "hash 1").unwrap();
+        store.add_user("carol", "hash 2").unwrap();
+        assert_eq!(
+            store.password_hash("carol").unwrap().as_deref(),
+            Some("hash 2")
+        );
+        assert!(store.roles("carol").unwrap().is_empty());
+        store.set_roles("carol", &["uploader", "auditor"]).unwrap();
+        store.add_user("carol", "hash 3").unwrap();
+        assert_eq!(store.roles("carol").unwrap(), ["auditor", "uploader"]);
+        store.set_roles("carol", &["approver"]).unwrap();
+        assert_eq!(store.roles("carol").unwrap(), ["approver"]);
+        assert_eq!(store.users().unwrap(), ["carol"]);
+    }
+
+    #[test]
+    fn test_download_audit() {
+        let store = Store::open_in_memory().unwrap();
+        store
+            .record_download("r1", "a", Some("127.0.0.1"), Some("alice"), "served")
+            .unwrap();
+        store
+            .record_download("r1", "d", None, None, "expired")
+            .unwrap();
+        store
+            .record_download("r2", "a", None, None, "served")
+            .unwrap();
+
+        let downloads = store.downloads("r1").unwrap();
+        let outcomes: Vec<_> = downloads
+            .iter()
+            .map(|d| (d.kind.as_str(), d.client.as_deref(), d.outcome.as_str()))
+            .collect();
+        assert_eq!(
+            outcomes,
+            [("a", Some("127.0.0.1"), "served"), ("d", None, "expired")]
+        );
+        assert_eq!(downloads[0].user.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn test_mark_interrupted() {
+        let buf = std::fs::read_to_string("data/onerow.xml").unwrap();
+        let rows = crate::xml_parser::parse_xml(&buf).unwrap().row;
+
+        let store = Store::open_in_memory().unwrap();
+        store.create_batch("b1", &rows).unwrap();
+        store.create_batch("b2", &rows).unwrap();
+        store.create_batch("b3", &rows).unwrap();
+        store.set_batch_status("b1", BatchStatus::Running).unwrap();
+        // approved, but the process stopped before its job started
+        store.set_batch_status("b3", BatchStatus::Approved).unwrap();
+        assert_eq!(store.row_count("b1").unwrap(), 1);
+        assert_eq!(store.batch_row("b1", 0).unwrap().as_ref(), rows.first());
+
+        assert_eq!(store.mark_interrupted().unwrap(), ["b1", "b3"]);
+        assert_eq!(
+            store.batch_status("b1").unwrap(),
+            Some(BatchStatus::Interrupted)
+        );
+        assert_eq!(store.row_status("b1", 0).unwrap(), Some(RowStatus::Pending));
+
+        assert!(store.resume_batch("b1", "dave").unwrap());
+        assert!(!store.resume_batch("b1", "erin").unwrap());
+        assert!(!store.resume_batch("b2", "dave").unwrap());
+        assert_eq!(
+            store.batch_status("b1").unwrap(),
+            Some(BatchStatus::Running)
+        );
+        assert_eq!(
+            store
+                .transitions("b1")
+                .unwrap()
+                .last()
+                .unwrap()
+                .user
+                .as_deref(),
+            Some("dave")
+        );
+
+        assert!(!store.abandon_batch("b2", "dave").unwrap());
+        assert!(store.abandon_batch("b3", "dave").unwrap());
+        assert!(!store.abandon_batch("b3", "dave").unwrap());
+        assert_eq!(
+            store
+                .transitions("b3")
+                .unwrap()
+                .last()
+                .unwrap()
+                .user
+                .as_deref(),
+            Some("dave")
+        );
+
+        // a resumed batch whose job did not start can be resumed again
+        assert!(store
+            .job_not_started("b1", BatchStatus::Running, BatchStatus::Interrupted, "dave")
+            .unwrap());
+        assert!(store.resume_batch("b1", "erin").unwrap());
+        // an approved one fails, with who approved it
+        store.set_batch_status("b2", BatchStatus::Approved).unwrap();
+        assert!(store
+            .job_not_started("b2", BatchStatus::Approved, BatchStatus::Failed, "erin")
+            .unwrap());
+        assert!(!store
+            .job_not_started("b2", BatchStatus::Approved, BatchStatus::Failed, "erin")
+            .unwrap());
+        let last = store.transitions("b2").unwrap().pop().unwrap();
+        assert_eq!(
+            (last.from, last.to, last.user.as_deref()),
+            (
+                Some(BatchStatus::Approved),
+                BatchStatus::Failed,
+                Some("erin")
+            )
+        );
+    }
+}
@@ -0,0 +1,502 @@
+#![doc = r"checks every uploaded row before anything is recorded"]
+
+use std::fmt;
+
+use crate::{
+    aba::{is_valid_routing, AchDirectory},
+    input::{Position, RowStream, FIELDS, OPTIONAL_FIELDS},
+    money::Money,
+    xml_parser::Row,
+    Error, Result,
+};
+
+const US_STATES: &[&str] = &[
+    "AK", "AL", "AR", "AS", "AZ", "CA", "CO", "CT", "DC", "DE", "FL", "GA", "GU", "HI", "IA", "ID",
+    "IL", "IN", "KS", "KY", "LA", "MA", "MD", "ME", "MI", "MN", "MO", "MP", "MS", "MT", "NC", "ND",
+    "NE", "NH", "NJ", "NM", "NV", "NY", "OH", "OK", "OR", "PA", "PR", "RI", "SC", "SD", "TN", "TX",
+    "UT", "VA", "VI", "VT", "WA", "WI", "WV", "WY",
+];
+
+/// One problem with one row of an upload.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowError {
+    pub row: usize,
+    pub position: Position,
+    /// The element path, e.g. `Employee/DOB`.
+    pub field: String,
+    pub reason: String,
+}
+
+impl fmt::Display for RowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "row {} (line {}, column {}): {} {}",
+            self.row, self.position.line, self.position.column, self.field, self.reason
+        )
+    }
+}
+
+type Check = fn(&str) -> Option<String>;
+
+/// What each text element must look like.
+const CHECKS: &[(&str, Check)] = &[
+    ("Employee/DunkinId", check_present),
+    ("Employee/DunkinBranch", check_present),
+    ("Employee/FirstName", check_present),
+    ("Employee/LastName", check_present),
+    ("Employee/DOB", check_dob),
+    ("Employee/PhoneNumber", check_phone),
+    ("Employee/Email", check_email),
+    ("Payor/DunkinId", check_present),
+    ("Payor/ABARouting", check_routing),
+    ("Payor/AccountNumber", check_present),
+    ("Payor/Name", check_present),
+    ("Payor/EIN", check_ein),
+    ("Payor/Address/Line1", check_present),
+    ("Payor/Address/City", check_present),
+    ("Payor/Address/State", check_state),
+    ("Payor/Address/Zip", check_zip),
+    ("Payee/PlaidId", check_present),
+    ("Payee/LoanAccountNumber", check_present),
+];
+
+/// Run [`CHECKS`] over whichever fields `field` can find.
+fn check_fields<'a>(field: impl Fn(&str) -> Option<&'a str>) -> Vec<(&'static str, String)> {
+    CHECKS
+        .iter()
+        .filter_map(|&(path, check)| Some((path, check(field(path)?)?)))
+        .collect()
+}
+
+/// The problems with a row that deserialized, as `(field, reason)`.
+pub fn check_row(row: &Row) -> Vec<(&'static str, String)> {
+    let mut problems = check_fields(|path| {
+        if path == "Employee/Email" {
+            return row.employee.email.as_deref();
+        }
+        Some(match path {
+            "Employee/DunkinId" => &row.employee.dunkin_id,
+            "Employee/DunkinBranch" => &row.employee.dunkin_branch,
+            "Employee/FirstName" => &row.employee.first_name,
+            "Employee/LastName" => &row.employee.last_name,
+            "Employee/DOB" => &row.employee.dob,
+            "Employee/PhoneNumber" => &row.employee.phone_number,
+            "Payor/DunkinId" => &row.payor.dunkin_id,
+            "Payor/ABARouting" => &row.payor.abarouting,
+            "Payor/AccountNumber" => &row.payor.account_number,
+            "Payor/Name" => &row.payor.name,
+            "Payor/EIN" => &row.payor.ein,
+            "Payor/Address/Line1" => &row.payor.address.line1,
+            "Payor/Address/City" => &row.payor.address.city,
+            "Payor/Address/State" => &row.payor.address.state,
+            "Payor/Address/Zip" => &row.payor.address.zip,
+            "Payee/PlaidId" => &row.payee.plaid_id,
+            "Payee/LoanAccountNumber" => &row.payee.account_number,
+            _ => return None,
+        })
+    });
+    if !row.amount.is_positive() {
+        problems.push(("Amount", "must be greater than zero".to_string()));
+    }
+    problems
+}
+
+fn check_present(value: &str) -> Option<String> {
+    value.trim().is_empty().then(|| "is empty".to_string())
+}
+
+fn digits(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// `MM-DD-YYYY`, a real calendar date.
+fn check_dob(dob: &str) -> Option<String> {
+    let invalid = || Some(format!("{dob:?} is not a MM-DD-YYYY date"));
+    let parts: Vec<&str> = dob.split('-').collect();
+    let [month, day, year] = parts[..] else {
+        return invalid();
+    };
+    if month.len() != 2 || day.len() != 2 || year.len() != 4 {
+        return invalid();
+    }
+    if !(digits(month) && digits(day) && digits(year)) {
+        return invalid();
+    }
+    let (month, day, year) = (
+        month.parse::<u32>().ok()?,
+        day.parse::<u32>().ok()?,
+        year.parse::<u32>().ok()?,
+    );
+
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return Some(format!("{dob:?} has no month {month}")),
+    };
+    if day == 0 || day > days_in_month {
+        return Some(format!("{dob:?} has no day {day}"));
+    }
+    if year < 1900 {
+        return Some(format!("{dob:?} is before 1900"));
+    }
+    None
+}
+
+fn check_phone(phone: &str) -> Option<String> {
+    to_e164(phone).err()
+}
+
+/// Normalize a US number, written with or without the country code and
+/// punctuation, to E.164, e.g. `+16366718717`. Every employee is in the
+/// US, so nothing else is accepted.
+pub fn to_e164(phone: &str) -> std::result::Result<String, String> {
+    let mut number = String::new();
+    for c in phone.chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            '+' | '(' | ')' | '-' | '.' | ' ' => {}
+            _ => return Err(format!("{phone:?} is not a phone number")),
+        }
+    }
+    let national = match number.len() {
+        10 => number.as_str(),
+        11 if number.starts_with('1') => &number[1..],
+        _ => return Err(format!("{phone:?} is not a 10 digit US number")),
+    };
+    if national.starts_with(['0', '1']) {
+        return Err(format!("{phone:?} has an invalid area code"));
+    }
+    if national[3..].starts_with(['0', '1']) {
+        return Err(format!("{phone:?} has an invalid exchange code"));
+    }
+    Ok(format!("+1{national}"))
+}
+
+/// Optional, but has to look like an address when given.
+fn check_email(email: &str) -> Option<String> {
+    let email = email.trim();
+    if email.is_empty() {
+        return None;
+    }
+    let valid = match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !email.contains(char::is_whitespace)
+        }
+        None => false,
+    };
+    (!valid).then(|| format!("{email:?} is not an email address"))
+}
+
+fn check_routing(routing: &str) -> Option<String> {
+    if routing.len() != 9 || !digits(routing) {
+        return Some(format!("{routing:?} is not a 9 digit routing number"));
+    }
+    if !is_valid_routing(routing) {
+        return Some(format!("{routing:?} fails the ABA checksum"));
+    }
+    None
+}
+
+/// A routing number that is well formed but unknown to `directory`.
+fn check_bank(directory: Option<&AchDirectory>, routing: &str) -> Option<(&'static str, String)> {
+    let directory = directory?;
+    if !is_valid_routing(routing) || directory.lookup(routing).is_some() {
+        return None;
+    }
+    Some((
+        "Payor/ABARouting",
+        format!("{routing:?} is not in the Fed ACH directory"),
+    ))
+}
+
+/// `12-3456789`, the dash being optional.
+fn check_ein(ein: &str) -> Option<String> {
+    let number = match ein.split_once('-') {
+        Some((prefix, rest)) if prefix.len() == 2 => format!("{prefix}{rest}"),
+        Some(_) => return Some(format!("{ein:?} is not a NN-NNNNNNN EIN")),
+        None => ein.to_string(),
+    };
+    if number.len() != 9 || !digits(&number) {
+        return Some(format!("{ein:?} is not a NN-NNNNNNN EIN"));
+    }
+    None
+}
+
+fn check_state(state: &str) -> Option<String> {
+    if !US_STATES.contains(&state) {
+        return Some(format!("{state:?} is not a US state code"));
+    }
+    None
+}
+
+/// `12345` or `12345-6789`.
+fn check_zip(zip: &str) -> Option<String> {
+    let valid = match zip.split_once('-') {
+        Some((zip5, plus4)) => zip5.len() == 5 && digits(zip5) && plus4.len() == 4 && digits(plus4),
+        None => zip.len() == 5 && digits(zip),
+    };
+    (!valid).then(|| format!("{zip:?} is not a US ZIP code"))
+}
+
+/// Wraps a [`RowStream`], yielding only the rows that pass validation and
+/// collecting the problems with the rest. Once the document is read, a
+/// single error follows if there were any, so importing through it records
+/// either the whole file or nothing.
+pub struct Validated<'a, S: RowStream> {
+    rows: S,
+    directory: Option<&'a AchDirectory>,
+    index: usize,
+    errors: Vec<RowError>,
+    finished: bool,
+}
+
+pub fn validate<'a, S: RowStream>(rows: S) -> Validated<'a, S> {
+    Validated {
+        rows,
+        directory: None,
+        index: 0,
+        errors: Vec::new(),
+        finished: false,
+    }
+}
+
+impl<'a, S: RowStream> Validated<'a, S> {
+    /// Also reject routing numbers missing from `directory`, if loaded.
+    pub fn with_directory(mut self, directory: Option<&'a AchDirectory>) -> Self {
+        self.directory = directory;
+        self
+    }
+
+    /// Every problem found so far.
+    pub fn errors(&self) -> &[RowError] {
+        &self.errors
+    }
+
+    fn push(&mut self, row: usize, field: &str, reason: String) {
+        self.errors.push(RowError {
+            row,
+            position: self.rows.source().position_of(field),
+            field: field.to_string(),
+            reason,
+        });
+    }
+
+    /// Turn a row that did not deserialize into its problems.
+    fn rejected(&mut self, row: usize, e: Error) {
+        if self.rows.is_done() {
+            // not a row problem, the document itself is broken
+            let reason = match e {
+                Error::Validation { reason, .. } => reason,
+                e => format!("is not well formed: {e}"),
+            };
+            self.errors.push(RowError {
+                row,
+                position: self.rows.cursor(),
+                field: self.rows.format().name().to_string(),
+                reason,
+            });
+            return;
+        }
+
+        let source = self.rows.source();
+        let mut problems: Vec<(&str, String)> = FIELDS
+            .iter()
+            .filter(|path| !OPTIONAL_FIELDS.contains(path) && source.element(path).is_none())
+            .map(|&path| (path, "is missing".to_string()))
+            .collect();
+        problems.extend(check_fields(|path| {
+            source.element(path).map(|e| e.text.as_str())
+        }));
+        if let Some(e) = source.element("Payor/ABARouting") {
+            problems.extend(check_bank(self.directory, &e.text));
+        }
+        if let Some(Err(Error::Validation { reason, .. })) =
+            source.element("Amount").map(|e| Money::parse(&e.text))
+        {
+            problems.push(("Amount", reason));
+        }
+        if problems.is_empty() {
+            problems.push(("row", e.to_string()));
+        }
+
+        for (field, reason) in problems {
+            self.push(row, field, reason);
+        }
+    }
+}
+
+impl<S: RowStream> Iterator for Validated<'_, S> {
+    type Item = Result<Row>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let Some(next) = self.rows.next() else {
+                if self.errors.is_empty() || self.finished {
+                    return None;
+                }
+                self.finished = true;
+                return Some(Err(Error::validation(
+                    "file",
+                    format!("{} problems found", self.errors.len()),
+                )));
+            };
+
+            let i = self.index;
+            self.index += 1;
+            match next {
+                Ok(row) => {
+                    let mut problems = check_row(&row);
+                    problems.extend(check_bank(self.directory, &row.payor.abarouting));
+                    if problems.is_empty() {
+                        return Some(Ok(row));
+                    }
+                    for (field, reason) in problems {
+                        self.push(i, field, reason);
+                    }
+                }
+                Err(e) => self.rejected(i, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::Format;
+    use crate::xml_parser::stream_rows;
+
+    #[test]
+    fn test_validate_collects_every_problem() {
+        let buf = std::fs::read_to_string("data/onerow.xml").unwrap();
+        let row_xml = &buf[buf.find("<row>").unwrap()..buf.find("</root>").unwrap()];
+        let bad_dob = row_xml.replace("11-29-1997", "02-30-1997");
+        let bad_payor = row_xml
+            .replace("021000021", "02100002")
+            .replace("32-1202402", "321-202402")
+            .replace("<State>IA</State>", "<State>XX</State>")
+            .replace("67485", "6748");
+        let bad_checksum = row_xml.replace("021000021", "021000022");
+        let bad_phone = row_xml.replace("+1 (636) 671-8717", "671-8717");
+        let no_name = row_xml.replace("<FirstName>Rebekah</FirstName>", "");
+        let bad_amount = row_xml.replace("$4.34", "$4.345").replace("IA", "XX");
+        let doc = format!(
+            "<root>{row_xml}{bad_dob}{bad_payor}{bad_checksum}{bad_phone}{no_name}{bad_amount}{row_xml}</root>"
+        );
+
+        let mut rows = validate(stream_rows(doc.as_bytes()));
+        let results: Vec<_> = rows.by_ref().collect();
+        // the two good rows, then the error that rolls back an import
+        assert_eq!(results.len(), 3);
+        assert!(results[2].is_err());
+
+        let found: Vec<_> = rows
+            .errors()
+            .iter()
+            .map(|e| (e.row, e.field.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (1, "Employee/DOB"),
+                (2, "Payor/ABARouting"),
+                (2, "Payor/EIN"),
+                (2, "Payor/Address/State"),
+                (2, "Payor/Address/Zip"),
+                (3, "Payor/ABARouting"),
+                (4, "Employee/PhoneNumber"),
+                (5, "Employee/FirstName"),
+                (6, "Payor/Address/State"),
+                (6, "Amount"),
+            ]
+        );
+
+        // every row of the fixture spans the same number of lines
+        let lines = row_xml.matches('\n').count();
+        let dob = &rows.errors()[0];
+        assert_eq!(dob.position.line, 1 + lines + 6);
+        assert_eq!(dob.position.column, 12);
+        assert!(dob.reason.contains("no day 30"), "{}", dob.reason);
+        assert!(rows.errors()[5].reason.contains("checksum"));
+        let amount = &rows.errors()[9];
+        assert_eq!(amount.position.line, 1 + 6 * lines + 27);
+    }
+
+    #[test]
+    fn test_contact_details() {
+        assert_eq!(to_e164("636-671-8717").unwrap(), "+16366718717");
+        assert_eq!(to_e164("+1 (636) 671.8717").unwrap(), "+16366718717");
+        assert_eq!(to_e164("16366718717").unwrap(), "+16366718717");
+        assert!(to_e164("671-8717").is_err());
+        assert!(to_e164("+44 20 7946 0958").is_err());
+        assert!(to_e164("136-671-8717").is_err());
+        assert!(to_e164("636-171-8717").is_err());
+        assert!(to_e164("636-671-871x").is_err());
+
+        assert!(check_email("").is_none());
+        assert!(check_email("rebekah@example.com").is_none());
+        assert!(check_email("rebekah").is_some());
+        assert!(check_email("rebekah@example").is_some());
+        assert!(check_email("@example.com").is_some());
+        assert!(check_email("re bekah@example.com").is_some());
+    }
+
+    #[test]
+    fn test_validate_against_directory() {
+        let buf = std::fs::read_to_string("data/onerow.xml").unwrap();
+        let directory = AchDirectory::read(&b"011000015O0110000151020805000000000"[..]).unwrap();
+
+        let mut rows = validate(stream_rows(buf.as_bytes()));
+        assert!(rows.all(|row| row.is_ok()));
+
+        let mut rows = validate(stream_rows(buf.as_bytes())).with_directory(Some(&directory));
+        assert!(rows.by_ref().all(|row| row.is_err()));
+        assert_eq!(rows.errors().len(), 1);
+        assert!(rows.errors()[0].reason.contains("Fed ACH directory"));
+    }
+
+    #[test]
+    fn test_validate_broken_document() {
+        let buf = std::fs::read_to_string("data/onerow.xml").unwrap();
+        let truncated = &buf[..buf.len() - 40];
+
+        let mut rows = validate(stream_rows(truncated.as_bytes()));
+        assert!(rows.next().unwrap().is_err());
+        assert!(rows.next().is_none());
+        assert_eq!(rows.errors().len(), 1);
+        assert_eq!(rows.errors()[0].field, "XML");
+
+        // labelled with the format it was read as
+        let config = crate::input::InputConfig::default();
+        let broken = |format, doc: &[u8]| {
+            let mut rows = validate(crate::input::open(format, doc, &config));
+            while rows.next().is_some() {}
+            let e = &rows.errors()[0];
+            (e.field.clone(), e.reason.clone())
+        };
+        let (field, reason) = broken(Format::Json, b"[{\"Amount\": ");
+        assert_eq!(field, "JSON");
+        assert!(reason.starts_with("is not well formed"), "{reason}");
+        let (field, reason) = broken(Format::Json, b"{\"rows\": []}");
+        assert_eq!(
+            (field.as_str(), reason.as_str()),
+            ("JSON", "has no \"row\" key")
+        );
+        let (field, _) = broken(Format::Csv, b"Amo\xffunt\n1\n");
+        assert_eq!(field, "CSV");
+
+        let huge = vec![b' '; crate::input::MAX_JSON_BYTES as usize + 1];
+        let (field, reason) = broken(Format::Json, &huge);
+        assert_eq!(field, "JSON");
+        assert!(reason.starts_with("is larger than 16 MiB"), "{reason}");
+    }
+}
@@ -1,16 +1,24 @@
-use quick_xml::DeError;
+use std::io::BufRead;
+
+use quick_xml::{events::Event, DeError, Reader};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize, Serialize)]
+use crate::{
+    input::{Element, Format, Position, RowSource, Rows, SourceReader},
+    mapping::Mapping,
+    money::Money,
+};
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 #[serde(rename_all = "PascalCase")]
 pub struct Row {
     pub employee: Employee,
     pub payor: Payor,
     pub payee: Payee,
-    pub amount: String,
+    pub amount: Money,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 #[serde(rename_all = "PascalCase")]
 pub struct Employee {
     pub dunkin_id: String,
@@ -20,9 +28,11 @@ pub struct Employee {
     #[serde(rename = "DOB")]
     pub dob: String,
     pub phone_number: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 #[serde(rename_all = "PascalCase")]
 pub struct Payor {
     pub dunkin_id: String,
@@ -37,7 +47,7 @@ pub struct Payor {
     pub address: Address,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 #[serde(rename_all = "PascalCase")]
 pub struct Address {
     pub line1: String,
@@ -46,7 +56,7 @@ pub struct Address {
     pub zip: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 #[serde(rename_all = "PascalCase")]
 pub struct Payee {
     pub plaid_id: String,
@@ -66,7 +76,7 @@ impl Root {
     }
 }
 
-fn parse_row(xml: &str) -> Result<Row, DeError> {
+pub fn parse_row(xml: &str) -> Result<Row, DeError> {
     quick_xml::de::from_str(xml)
 }
 
@@ -74,11 +84,140 @@ pub fn parse_xml(xml: &str) -> Result<Root, DeError> {
     quick_xml::de::from_str(xml)
 }
 
+/// Keeps count of the lines and columns the XML reader has consumed.
+struct LineCounter<R> {
+    inner: R,
+    position: Position,
+}
+
+impl<R: BufRead> std::io::Read for LineCounter<R> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        let n = std::io::Read::read(&mut self.fill_buf()?, out)?;
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for LineCounter<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        // the bytes are still buffered, so this does no I/O
+        if let Ok(buf) = self.inner.fill_buf() {
+            for &b in &buf[..amt.min(buf.len())] {
+                if b == b'\n' {
+                    self.position.line += 1;
+                    self.position.column = 1;
+                } else {
+                    self.position.column += 1;
+                }
+            }
+        }
+        self.inner.consume(amt)
+    }
+}
+
+/// Reads the `<row>`s of a document one at a time, so only the current
+/// row is ever held in memory.
+pub struct RowReader<R: BufRead> {
+    reader: Reader<LineCounter<R>>,
+    buf: Vec<u8>,
+    row_tag: String,
+}
+
+impl<R: BufRead> RowReader<R> {
+    /// Rows are the elements named `row_tag`, wherever they are.
+    pub fn new(input: R, row_tag: impl Into<String>) -> Self {
+        let mut reader = Reader::from_reader(LineCounter {
+            inner: input,
+            position: Position::default(),
+        });
+        reader.trim_text(true);
+        Self {
+            reader,
+            buf: Vec::new(),
+            row_tag: row_tag.into(),
+        }
+    }
+
+    /// Read the elements of the row that just started, by their path below
+    /// it, e.g. `Employee/DOB`.
+    fn read_row(&mut self) -> Result<RowSource, DeError> {
+        let mut source = RowSource {
+            position: self.cursor(),
+            elements: Vec::new(),
+        };
+
+        let mut path: Vec<String> = Vec::new();
+        loop {
+            self.buf.clear();
+            let event = self.reader.read_event_into(&mut self.buf)?;
+            match &event {
+                Event::Start(e) | Event::Empty(e) => {
+                    path.push(String::from_utf8_lossy(e.name().as_ref()).into_owned());
+                    source.elements.push(Element {
+                        path: path.join("/"),
+                        text: String::new(),
+                        position: self.reader.get_ref().position,
+                    });
+                    if matches!(event, Event::Empty(_)) {
+                        path.pop();
+                    }
+                }
+                Event::Text(e) => {
+                    if let Some(element) = source.elements.last_mut() {
+                        element.text = e.unescape()?.into_owned();
+                    }
+                }
+                Event::CData(e) => {
+                    if let Some(element) = source.elements.last_mut() {
+                        element.text = String::from_utf8_lossy(e).into_owned();
+                    }
+                }
+                Event::End(_) if path.pop().is_none() => return Ok(source),
+                Event::Eof => return Err(DeError::UnexpectedEof),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl<R: BufRead> SourceReader for RowReader<R> {
+    fn format(&self) -> Format {
+        Format::Xml
+    }
+
+    fn cursor(&self) -> Position {
+        self.reader.get_ref().position
+    }
+
+    fn read_source(&mut self) -> Option<crate::Result<RowSource>> {
+        loop {
+            self.buf.clear();
+            match self.reader.read_event_into(&mut self.buf) {
+                Ok(Event::Start(e)) if e.name().as_ref() == self.row_tag.as_bytes() => {
+                    return Some(self.read_row().map_err(Into::into));
+                }
+                Ok(Event::Eof) => return None,
+                Ok(_) => {}
+                Err(e) => return Some(Err(DeError::from(e).into())),
+            }
+        }
+    }
+}
+
+/// Stream the `<row>`s of a document in this module's layout.
+pub fn stream_rows<R: BufRead>(input: R) -> Rows<RowReader<R>> {
+    Rows::new(RowReader::new(input, "row"), Mapping::default())
+}
+
 #[cfg(test)]
 mod tests {
     use std::{fs::File, io::Read};
 
-    use super::parse_xml;
+    use super::*;
 
     #[test]
     fn test_xml_parser() {
@@ -88,12 +227,38 @@ mod tests {
             .read_to_string(&mut buf)
             .unwrap();
 
-        dbg!(parse_xml(&buf));
+        dbg!(parse_xml(&buf)).unwrap();
 
         //let x = parse_xml(&buf).unwrap();
         //assert_eq!(x.to_string().unwrap(), buf)
     }
 
+    #[test]
+    fn test_stream_rows() {
+        let buf = std::fs::read_to_string("data/onerow.xml").unwrap();
+        let expected = parse_xml(&buf).unwrap().row;
+
+        let rows = stream_rows(buf.as_bytes())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(rows, expected);
+
+        // three copies of the row, streamed back one by one
+        let row_xml = &buf[buf.find("<row>").unwrap()..buf.find("</root>").unwrap()];
+        let many = format!("<root>{row_xml}{row_xml}{row_xml}</root>");
+        let rows = stream_rows(many.as_bytes())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[2], expected[0]);
+
+        // a truncated document ends the stream with an error
+        let truncated = &many[..many.len() - 40];
+        let results = stream_rows(truncated.as_bytes()).collect::<Vec<_>>();
+        assert_eq!(results.len(), 3);
+        assert!(results[2].is_err());
+    }
+
     // #[test]
     // fn test_row_to_csv() {
     //     let mut buf = String::new();